@@ -0,0 +1,108 @@
+#![allow(dead_code)]
+
+// Format independent metadata, every decoder converts its own header with `From`.
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba,
+    Indexed,
+}
+
+impl ColorSpace {
+    pub fn has_alpha(&self) -> bool {
+        match *self {
+            ColorSpace::GrayAlpha | ColorSpace::Rgba => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u64,
+    pub height: u64,
+    /// bits per sample, for netpbm the bits needed to hold maxval
+    pub bit_depth: u8,
+    /// samples per pixel, 1 for indexed images
+    pub channels: u8,
+    pub has_alpha: bool,
+    pub color_space: ColorSpace,
+}
+
+/// Bits needed to hold `maxval`, e.g. 8 for 255 and 16 for 65535.
+pub fn maxval_bit_depth(maxval: u16) -> u8 {
+    (16 - maxval.leading_zeros()) as u8
+}
+
+
+/// Where the first row of a pixel buffer sits in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// rows run top to bottom, as in PNG and netpbm
+    TopLeft,
+    /// rows run bottom to top, as in PFM and OpenGL textures
+    BottomLeft,
+}
+
+/// How the rows of a pixel buffer are laid out, pixels within a row always run left to right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelLayout {
+    pub origin: Origin,
+    /// bytes from the start of one row to the start of the next
+    pub row_stride: usize,
+}
+
+impl PixelLayout {
+    /// Copies `pixels` into a buffer with `origin`, reversing the row order if it differs.
+    pub fn to_origin(&self, origin: Origin, pixels: &[u8]) -> Vec<u8> {
+        if origin == self.origin || self.row_stride == 0 {
+            return pixels.to_vec();
+        }
+
+        let mut flipped: Vec<u8> = Vec::with_capacity(pixels.len());
+        for row in pixels.chunks(self.row_stride).rev() {
+            flipped.extend_from_slice(row);
+        }
+
+        flipped
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_depth_holds_maxval() {
+        let cases = [(1u16, 1u8), (3, 2), (15, 4), (16, 5), (255, 8), (256, 9), (1023, 10), (65535, 16)];
+        for &(maxval, bit_depth) in cases.iter() {
+            assert_eq!(maxval_bit_depth(maxval), bit_depth, "maxval {}", maxval);
+        }
+    }
+
+    #[test]
+    fn to_origin_flips_rows() {
+        let pixels: Vec<u8> = (0..6).collect();
+        let layout = PixelLayout { origin: Origin::BottomLeft, row_stride: 2 };
+
+        assert_eq!(layout.to_origin(Origin::TopLeft, &pixels), vec![4, 5, 2, 3, 0, 1]);
+        assert_eq!(layout.to_origin(Origin::BottomLeft, &pixels), pixels);
+
+        // flipping twice gives the rows back
+        let flipped = PixelLayout { origin: Origin::TopLeft, row_stride: 2 }.to_origin(Origin::BottomLeft, &pixels);
+        assert_eq!(layout.to_origin(Origin::TopLeft, &flipped), pixels);
+    }
+
+    #[test]
+    fn to_origin_with_zero_stride_is_a_copy() {
+        let pixels: Vec<u8> = (0..6).collect();
+        let layout = PixelLayout { origin: Origin::BottomLeft, row_stride: 0 };
+
+        assert_eq!(layout.to_origin(Origin::TopLeft, &pixels), pixels);
+    }
+}
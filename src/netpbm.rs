@@ -1,9 +1,10 @@
 #![feature(try_from, const_fn, duration_as_u128, nll)]
 #![allow(unused_variables, unused_imports, unused_mut, dead_code, unused_attributes)]
 
+use std::io;
 use std::iter::Iterator;
 use std::fs::{ File, OpenOptions };
-use std::io::{ Bytes, Read, Seek, SeekFrom };
+use std::io::{ Bytes, BufRead, Read, Seek, SeekFrom };
 
 
 // https://en.wikipedia.org/wiki/Netpbm_format#File_format_description
@@ -18,6 +19,10 @@ pub const PPM_BINARY_MAGIC_NUMBER: [u8; 2] = [80, 54]; // b"P6"
 
 pub const PAM_BINARY_MAGIC_NUMBER: [u8; 2] = [80, 55]; // b"P7"
 
+// http://netpbm.sourceforge.net/doc/pfm.html
+pub const PFM_GRAYSCALE_MAGIC_NUMBER: [u8; 2] = [80, 102]; // b"Pf"
+pub const PFM_RGB_MAGIC_NUMBER: [u8; 2]       = [80, 70];  // b"PF"
+
 pub const LF: char = '\n';
 pub const CR: char = '\r';
 
@@ -27,6 +32,87 @@ pub struct Lines<R: Read + Seek> {
     pub handle: R,
 }
 
+impl<RS: Read + Seek> Lines<RS> {
+    /// Byte offset of the handle. Right after the last header value has been
+    /// read this is where the raster starts.
+    pub fn position(&mut self) -> io::Result<u64> {
+        self.handle.seek(SeekFrom::Current(0))
+    }
+
+    /// Moves the handle right after the single whitespace byte that ended the last token,
+    /// where the raster of PPM and PFM starts. `next` takes a CR/LF pair as one separator,
+    /// the second byte of a pair is pixel data and is given back here.
+    /// Fails if the token ran into the end of the file.
+    pub fn raster_start(&mut self) -> io::Result<u64> {
+        let pos = self.position()?;
+        if pos < 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut tail = [0u8; 2];
+        self.handle.seek(SeekFrom::Start(pos - 2))?;
+        self.handle.read_exact(&mut tail)?;
+
+        let is_whitespace = |byte: u8| (byte as char).is_whitespace();
+
+        // the last token byte is never whitespace, so two whitespace bytes are a pair
+        if is_whitespace(tail[0]) && is_whitespace(tail[1]) {
+            self.handle.seek(SeekFrom::Start(pos - 1))
+        } else if is_whitespace(tail[1]) {
+            Ok(pos)
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Moves the handle right after the line break that ended the last token, where the
+    /// PAM raster starts after `ENDHDR`. A CR/LF pair is one break, but of an LF/CR pair
+    /// only the LF is: the CR is pixel data and is given back.
+    /// Returns the byte that ended the token, fails if the token ran into the end of the file.
+    pub fn line_end(&mut self) -> io::Result<u8> {
+        let pos = self.position()?;
+        if pos < 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut tail = [0u8; 2];
+        self.handle.seek(SeekFrom::Start(pos - 2))?;
+        self.handle.read_exact(&mut tail)?;
+
+        let is_whitespace = |byte: u8| (byte as char).is_whitespace();
+
+        // the last token byte is never whitespace, so two whitespace bytes are a pair
+        if is_whitespace(tail[0]) && is_whitespace(tail[1]) {
+            if tail == [b'\n', b'\r'] {
+                self.handle.seek(SeekFrom::Start(pos - 1))?;
+            }
+            Ok(tail[0])
+        } else if is_whitespace(tail[1]) {
+            Ok(tail[1])
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Skips the rest of a comment whose `#` token was just read. A comment runs to the end
+    /// of the line, across the blanks `next` splits tokens at.
+    pub fn skip_comment(&mut self) -> io::Result<()> {
+        let end = self.line_end()?;
+        let mut byte = [end];
+
+        while byte[0] != b'\n' && byte[0] != b'\r' {
+            self.handle.read_exact(&mut byte)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives back the handle for reading the raster as plain bytes.
+    pub fn into_handle(self) -> RS {
+        self.handle
+    }
+}
+
 impl<RS: Read + Seek> Iterator for Lines<RS> {
     type Item = Vec<u8>;
 
@@ -92,6 +178,148 @@ impl<RS: Read + Seek> Iterator for Lines<RS> {
 
 
 
+/// A plain (ASCII) PBM, PGM or PPM image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlainImage {
+    pub magic: [u8; 2],
+    pub width: u64,
+    pub height: u64,
+    /// always 1 for PBM
+    pub maxval: u16,
+    /// row-major, one sample per pixel for P1/P2 and three for P3
+    pub samples: Vec<u16>,
+}
+
+struct Tokens<R: BufRead> {
+    bytes: Bytes<R>,
+}
+
+impl<R: BufRead> Tokens<R> {
+    fn next_token(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut token: Vec<u8> = Vec::new();
+
+        while let Some(byte) = self.bytes.next() {
+            let byte = byte?;
+
+            if byte == b'#' {
+                // comments run to the end of the line
+                while let Some(byte) = self.bytes.next() {
+                    let byte = byte?;
+                    if byte == b'\n' || byte == b'\r' {
+                        break;
+                    }
+                }
+            } else if byte.is_ascii_whitespace() {
+                if token.len() > 0 {
+                    return Ok(Some(token));
+                }
+            } else {
+                token.push(byte);
+            }
+        }
+
+        Ok(if token.len() > 0 { Some(token) } else { None })
+    }
+
+    fn next_number(&mut self) -> io::Result<u64> {
+        match self.next_token()? {
+            Some(token) => {
+                match String::from_utf8(token).ok().and_then(|s| s.parse::<u64>().ok()) {
+                    Some(n) => Ok(n),
+                    None => Err(invalid_data("invalid number")),
+                }
+            },
+            None => Err(invalid_data("unexpected end of file")),
+        }
+    }
+}
+
+/// Reads exactly `length` bytes of raster data. The buffer grows with what is actually
+/// read, never to a length taken from a header the file controls up front.
+pub fn read_raster<R: Read>(handle: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    handle.take(length).read_to_end(&mut data)?;
+
+    if (data.len() as u64) < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(data)
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a plain P1, P2 or P3 image in a single forward pass.
+///
+/// Unlike the binary decoders this needs no `Seek`, so it works on stdin and pipes.
+pub fn read_plain<R: BufRead>(reader: R) -> io::Result<PlainImage> {
+    let mut tokens = Tokens { bytes: reader.bytes() };
+
+    let magic: [u8; 2] = match tokens.next_token()? {
+        Some(ref token) if token.len() == 2 => [ token[0], token[1] ],
+        _ => return Err(invalid_data("invalid signature")),
+    };
+
+    let channels: u64 = match magic {
+        PBM_ASCII_MAGIC_NUMBER | PGM_ASCII_MAGIC_NUMBER => 1,
+        PPM_ASCII_MAGIC_NUMBER => 3,
+        _ => return Err(invalid_data("invalid signature")),
+    };
+
+    let width = tokens.next_number()?;
+    let height = tokens.next_number()?;
+    let maxval = if magic == PBM_ASCII_MAGIC_NUMBER { 1 } else { tokens.next_number()? };
+
+    if maxval < 1 || maxval > 65535 {
+        return Err(invalid_data("invalid maxval"));
+    }
+
+    let count = width.checked_mul(height)
+                     .and_then(|n| n.checked_mul(channels))
+                     .ok_or(invalid_data("image too large"))?;
+
+    let mut samples: Vec<u16> = Vec::new();
+
+    while (samples.len() as u64) < count {
+        if magic == PBM_ASCII_MAGIC_NUMBER {
+            // PBM bits need no separating whitespace: "0110" is four pixels
+            let token = match tokens.next_token()? {
+                Some(token) => token,
+                None => return Err(invalid_data("unexpected end of file")),
+            };
+
+            for byte in token {
+                match byte {
+                    b'0' => samples.push(0),
+                    b'1' => samples.push(1),
+                    _ => return Err(invalid_data("invalid sample")),
+                }
+            }
+        } else {
+            let sample = tokens.next_number()?;
+            if sample > maxval {
+                return Err(invalid_data("sample exceeds maxval"));
+            }
+            samples.push(sample as u16);
+        }
+    }
+
+    if samples.len() as u64 != count {
+        return Err(invalid_data("too many samples"));
+    }
+
+    Ok(PlainImage {
+        magic: magic,
+        width: width,
+        height: height,
+        maxval: maxval as u16,
+        samples: samples,
+    })
+}
+
+
 fn main() {
     let filepath = "output.pam";
     let mut file = File::open(filepath).unwrap();
@@ -104,3 +332,66 @@ fn main() {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_lines_then_raw_pixels() {
+        let file = b"P5\n3 1\n255\n\x0a\x20\x0d".to_vec();
+        let mut lines = Lines { handle: Cursor::new(file) };
+
+        let tokens: Vec<Vec<u8>> = (&mut lines).take(4).collect();
+        assert_eq!(tokens[0], b"P5");
+        assert_eq!(tokens[2], b"1");
+        assert_eq!(tokens[3], b"255");
+
+        let start = lines.raster_start().unwrap();
+        assert_eq!(lines.position().unwrap(), start);
+
+        let mut handle = lines.into_handle();
+        assert_eq!(read_raster(&mut handle, 3).unwrap(), vec![0x0a, 0x20, 0x0d]);
+    }
+
+    fn plain(file: &[u8]) -> io::Result<PlainImage> {
+        read_plain(file)
+    }
+
+    fn invalid(result: io::Result<PlainImage>) -> String {
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => e.to_string(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_pbm_bits_may_be_packed() {
+        let image = plain(b"P1\n# 4x2\n4 2\n0110\n1 0\n01\n").unwrap();
+        assert_eq!((image.magic, image.width, image.height, image.maxval), (PBM_ASCII_MAGIC_NUMBER, 4, 2, 1));
+        assert_eq!(image.samples, vec![0, 1, 1, 0, 1, 0, 0, 1]);
+
+        assert_eq!(invalid(plain(b"P1\n2 1\n012\n")), "invalid sample");
+    }
+
+    #[test]
+    fn plain_comments_may_end_a_token_line() {
+        let image = plain(b"P2 # gray\n2 # wide\n1\n# maxval next\n9\n3 # first\n4#second\n").unwrap();
+        assert_eq!((image.width, image.height, image.maxval), (2, 1, 9));
+        assert_eq!(image.samples, vec![3, 4]);
+
+        let image = plain(b"P3\n1 1 255 # comment 1 2 3\n10 20 # blue follows\r30\n").unwrap();
+        assert_eq!(image.samples, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn plain_samples_are_checked() {
+        assert_eq!(invalid(plain(b"P2\n2 1\n7\n1 8\n")), "sample exceeds maxval");
+        assert_eq!(invalid(plain(b"P3\n1 1\n255\n1 2\n")), "unexpected end of file");
+        assert_eq!(invalid(plain(b"P1\n3 1\n0110\n")), "too many samples");
+        assert_eq!(invalid(plain(b"P2\n1 1\n0\n")), "invalid maxval");
+        assert_eq!(invalid(plain(b"P5\n1 1\n255\n")), "invalid signature");
+    }
+}
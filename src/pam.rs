@@ -6,8 +6,11 @@
 
 
 mod netpbm;
+mod info;
 
 pub use self::netpbm::{ PAM_BINARY_MAGIC_NUMBER, Lines };
+use self::netpbm::read_raster;
+pub use self::info::{ ImageInfo, ColorSpace, Origin, PixelLayout };
 
 use std::io;
 use std::fmt;
@@ -38,6 +41,9 @@ impl From<io::Error> for Error {
 }
 
 
+const HEADER_KEYWORDS: [&'static str; 6] = ["WIDTH", "HEIGHT", "DEPTH", "MAXVAL", "TUPLTYPE", "ENDHDR"];
+
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
@@ -101,6 +107,54 @@ pub struct Header {
     pub color: Color,
 }
 
+impl Header {
+    /// Short description for display, e.g. "16-bit Grayscale" or "1-bit Black and White".
+    pub fn color_and_depth_description(&self) -> String {
+        let bitdepth = 16 - self.maxval.leading_zeros();
+        let color = match self.color {
+            Color::BlackAndWhite => "Black and White",
+            Color::Grayscale => "Grayscale",
+            Color::RGB => "RGB",
+            Color::BlackAndWhiteAlpha => "Black and White Alpha",
+            Color::GrayscaleAlpha => "Grayscale Alpha",
+            Color::RGBA => "RGBA",
+        };
+
+        format!("{}-bit {}", bitdepth, color)
+    }
+
+    /// Layout of the raw pixel data: top-left origin, rows of `depth` samples per pixel
+    /// (two bytes each above maxval 255) without padding.
+    pub fn pixel_layout(&self) -> PixelLayout {
+        let sample_size = if self.maxval > 255 { 2 } else { 1 };
+
+        PixelLayout {
+            origin: Origin::TopLeft,
+            row_stride: (self.width * self.depth as u64 * sample_size) as usize,
+        }
+    }
+}
+
+impl From<Header> for ImageInfo {
+    fn from(header: Header) -> ImageInfo {
+        let color_space = match header.color {
+            Color::BlackAndWhite | Color::Grayscale => ColorSpace::Gray,
+            Color::BlackAndWhiteAlpha | Color::GrayscaleAlpha => ColorSpace::GrayAlpha,
+            Color::RGB => ColorSpace::Rgb,
+            Color::RGBA => ColorSpace::Rgba,
+        };
+
+        ImageInfo {
+            width: header.width,
+            height: header.height,
+            bit_depth: info::maxval_bit_depth(header.maxval),
+            channels: header.depth,
+            has_alpha: color_space.has_alpha(),
+            color_space: color_space,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Data {
     pub offset: u64,
@@ -116,9 +170,13 @@ pub enum State {
     Data,
 }
 
+#[derive(Clone)]
 pub struct Decoder<RS: Read + Seek> {
     state: State,
     line_reader: Lines<RS>,
+    signature: Option<[u8; 2]>,
+    data: Option<Data>,
+    strict: bool,
     pixels_size: u64,
 }
 
@@ -128,29 +186,95 @@ impl<RS: Read + Seek> Decoder<RS> {
         Decoder {
             state: State::Pending,
             line_reader: Lines { handle: handle },
+            signature: None,
+            data: None,
+            strict: true,
             pixels_size: 0,
         }
     }
 
+    /// In strict mode (the default) the header must end with `ENDHDR`. In lenient mode
+    /// keywords are matched case-insensitively, blanks after `ENDHDR` are skipped up to
+    /// the line break and a complete set of fields followed by anything that isn't a keyword
+    /// ends the header, that value being the first pixel data.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Gives back the underlying handle, e.g. after iterating with `for elem in &mut decoder`.
+    pub fn into_inner(self) -> RS {
+        self.line_reader.into_handle()
+    }
+
+    /// Reads the signature from the current position of the handle, the decoder does not
+    /// rewind. Once read the signature is cached and later calls return it without reading.
     pub fn read_signature(&mut self) -> Result<[u8; 2], Error> {
+        if let Some(signature) = self.signature {
+            return Ok(signature);
+        }
+
         assert_eq!(self.state, State::Pending);
-        self.line_reader.handle.seek(SeekFrom::Start(0)).unwrap();
 
         if let Some(line) = self.line_reader.next() {
             if line.len() == 2 {
+                let signature = [ line[0], line[1], ];
+
+                self.signature = Some(signature);
                 self.state = State::Signature;
-                return Ok([ line[0], line[1], ])
+                return Ok(signature)
             }
         }
 
         Err(Error::InvalidSignature)
     }
 
+    /// Rewinds the handle to the start of the file and forgets everything read so far.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.line_reader.handle.seek(SeekFrom::Start(0))?;
+
+        self.state = State::Pending;
+        self.signature = None;
+        self.data = None;
+        self.pixels_size = 0;
+
+        Ok(())
+    }
+
+    /// Moves past spaces and tabs after `ENDHDR` up to and including the line break, when
+    /// `end`, the byte that ended the token, was a blank. The line break was already taken
+    /// otherwise, and the next byte is pixel data whatever its value.
+    fn skip_trailing_blanks(&mut self, end: u8) -> Result<(), Error> {
+        if end != b' ' && end != b'\t' {
+            return Ok(());
+        }
+
+        let mut byte = [0u8; 1];
+        loop {
+            self.line_reader.handle.read_exact(&mut byte)?;
+            match byte[0] {
+                b' ' | b'\t' => continue,
+                b'\n' => return Ok(()),
+                b'\r' => {
+                    // CR/LF is one line break
+                    if self.line_reader.handle.read(&mut byte)? == 1 && byte[0] != b'\n' {
+                        self.line_reader.handle.seek(SeekFrom::Current(-1))?;
+                    }
+                    return Ok(());
+                },
+                _ => {
+                    self.line_reader.handle.seek(SeekFrom::Current(-1))?;
+                    return Ok(());
+                },
+            }
+        }
+    }
+
     fn next_value(&mut self) -> Option<String> {
         if let Some(line) = self.line_reader.next() {
             if line.len() > 0 {
                 if line[0] == b'#' {
                     // COMMENT LINE
+                    self.line_reader.skip_comment().ok()?;
                     return self.next_value();
                 }
                 if let Ok(s) = String::from_utf8(line) {
@@ -177,15 +301,30 @@ impl<RS: Read + Seek> Decoder<RS> {
         //       however, FFmpeg requires it.
         let mut tupltype: Option<Color> = None;
 
+        // the header runs up to ENDHDR, stopping once every field is set
+        // would leave the ENDHDR line in front of the pixel data
         loop {
-            
-            if width.is_some() && height.is_some() &
-                & depth.is_some() && maxval.is_some() 
-                && tupltype.is_some() {
-                break;
+            let pos = self.line_reader.position()?;
+            let mut value = self.next_value();
+
+            if !self.strict {
+                value = value.map(|val| val.to_ascii_uppercase());
+
+                let complete = width.is_some() && height.is_some() && depth.is_some()
+                               && maxval.is_some() && tupltype.is_some();
+                let is_keyword = match value {
+                    Some(ref val) => HEADER_KEYWORDS.contains(&val.as_ref()),
+                    None => false,
+                };
+
+                if complete && !is_keyword {
+                    // implicit end of header, put the pixel data back
+                    self.line_reader.handle.seek(SeekFrom::Start(pos))?;
+                    break;
+                }
             }
 
-            match self.next_value() {
+            match value {
                 Some(val) => match val.as_ref() {
                     "WIDTH" => {
                         assert_eq!(width.is_none(), true);
@@ -253,6 +392,11 @@ impl<RS: Read + Seek> Decoder<RS> {
                         }
                     },
                     "ENDHDR" => {
+                        // a CR right after the newline is the first pixel byte
+                        let end = self.line_reader.line_end().map_err(|_| Error::InvalidHeader)?;
+                        if !self.strict {
+                            self.skip_trailing_blanks(end)?;
+                        }
                         break;
                     },
                     _ => {
@@ -276,11 +420,32 @@ impl<RS: Read + Seek> Decoder<RS> {
             color: tupltype.unwrap(),
         };
 
-        // bytes per pixel
-        let bpp = header.depth * (if header.maxval > 255 { 2 } else { 1 });
-        let pixels_size = header.width * header.height * (bpp as u64);
+        if header.width == 0 || header.height == 0 || header.maxval < 1 {
+            return Err(Error::InvalidHeader);
+        }
 
-        self.pixels_size = pixels_size;
+        // black and white samples are 0 or 1, other tuple types may use up to 65535
+        match header.color {
+            Color::BlackAndWhite | Color::BlackAndWhiteAlpha if header.maxval != 1 => {
+                return Err(Error::InvalidHeader);
+            },
+            _ => { },
+        }
+
+        // DEPTH is parsed as a u8, so 255 planes is the most accepted
+        if header.depth < 1 {
+            return Err(Error::InvalidHeader);
+        }
+
+        // bytes per pixel, widened before multiplying: 2 * depth overflows u8 above 127 planes
+        let bpp = header.depth as u64 * (if header.maxval > 255 { 2 } else { 1 });
+        let pixels_size = header.width.checked_mul(header.height)
+                                      .and_then(|n| n.checked_mul(bpp));
+
+        match pixels_size {
+            Some(pixels_size) => self.pixels_size = pixels_size,
+            None => return Err(Error::InvalidHeader),
+        }
 
         self.state = State::Header;
 
@@ -289,17 +454,113 @@ impl<RS: Read + Seek> Decoder<RS> {
 
     pub fn read_data(&mut self) -> Result<Data, Error> {
         assert_eq!(self.state, State::Header);
-        assert_eq!(self.pixels_size > 0, true);
 
-        let pos = self.line_reader.handle.seek(SeekFrom::Current(0)).unwrap();
+        let pos = self.line_reader.position()?;
 
-        self.state = State::Data;
-
-        Ok(Data {
+        let data = Data {
             offset: pos,
             length: self.pixels_size,
-        })
+        };
+
+        self.data = Some(data);
+        self.state = State::Data;
+
+        Ok(data)
     }
+
+    /// Moves past the pixel data of the current image to the next image of a multi-image
+    /// stream, e.g. from FFmpeg's `image2pipe`. Returns `false` at the end of the stream,
+    /// otherwise the decoder is ready to read the next signature and header.
+    pub fn next_image(&mut self) -> Result<bool, Error> {
+        let data = match self.data {
+            Some(data) => data,
+            None => return Err(Error::Other("pixel data not reached")),
+        };
+
+        self.line_reader.handle.seek(SeekFrom::Start(data.offset + data.length))?;
+
+        let mut byte = [0u8; 1];
+        if self.line_reader.handle.read(&mut byte)? == 0 {
+            return Ok(false);
+        }
+        self.line_reader.handle.seek(SeekFrom::Current(-1))?;
+
+        self.state = State::Pending;
+        self.signature = None;
+        self.data = None;
+        self.pixels_size = 0;
+
+        Ok(true)
+    }
+
+    /// Reads the samples as stored, BLACKANDWHITE tuples stay 0 or 1.
+    ///
+    /// Rows run top to bottom without padding, each pixel holds `depth` samples
+    /// and samples are one byte, or two big-endian bytes when maxval is above 255.
+    pub fn read_raw_pixels(&mut self, data: &Data) -> Result<Vec<u8>, Error> {
+        self.line_reader.handle.seek(SeekFrom::Start(data.offset))?;
+        let pixels = read_raster(&mut self.line_reader.handle, data.length)?;
+
+        Ok(pixels)
+    }
+
+    /// Reads the samples for display: the 0/1 samples of BLACKANDWHITE and
+    /// BLACKANDWHITE_ALPHA (where 1 is white or opaque) become 0/255.
+    pub fn read_pixels(&mut self, header: &Header, data: &Data) -> Result<Vec<u8>, Error> {
+        let mut pixels = self.read_raw_pixels(data)?;
+
+        match header.color {
+            Color::BlackAndWhite | Color::BlackAndWhiteAlpha => {
+                for sample in pixels.iter_mut() {
+                    if *sample > 1 {
+                        return Err(Error::InvalidImageData);
+                    }
+                    *sample *= 255;
+                }
+            },
+            _ => { },
+        }
+
+        Ok(pixels)
+    }
+}
+
+/// Reads the signature and the header from the current position of `handle`,
+/// without touching the pixels. The handle is left at the first byte of pixel data.
+pub fn read_header_only<RS: Read + Seek>(handle: &mut RS) -> Result<Header, Error> {
+    let mut decoder = Decoder::new(handle);
+    decoder.read_signature()?;
+    decoder.read_header()
+}
+
+/// Writes one image, its full header followed by `pixels` in the layout `read_raw_pixels`
+/// returns. Calling it again on the same writer appends the next image of a multi-image
+/// stream, which `Decoder::next_image` reads back.
+pub fn write_image<W: Write>(w: &mut W, header: &Header, pixels: &[u8]) -> Result<(), Error> {
+    if header.width == 0 || header.height == 0 || header.depth < 1 || header.maxval < 1 {
+        return Err(Error::InvalidHeader);
+    }
+
+    match header.color {
+        Color::BlackAndWhite | Color::BlackAndWhiteAlpha if header.maxval != 1 => {
+            return Err(Error::InvalidHeader);
+        },
+        _ => { },
+    }
+
+    let bpp = header.depth as u64 * (if header.maxval > 255 { 2 } else { 1 });
+    let pixels_size = header.width.checked_mul(header.height)
+                                  .and_then(|n| n.checked_mul(bpp));
+
+    if pixels_size != Some(pixels.len() as u64) {
+        return Err(Error::InvalidImageData);
+    }
+
+    write!(w, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+           header.width, header.height, header.depth, header.maxval, header.color)?;
+    w.write_all(pixels)?;
+
+    Ok(())
 }
 
 
@@ -381,6 +642,18 @@ impl<Handle: Read + Seek> Iterator for Decoder<Handle> {
             None
         }
     }
+
+    /// Counts the elements left of signature, header and data, fewer only if the file is malformed.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = match self.state {
+            State::Pending => 3,
+            State::Signature => 2,
+            State::Header => 1,
+            State::Data => 0,
+        };
+
+        (remaining, Some(remaining))
+    }
 }
 
 
@@ -412,3 +685,237 @@ fn main (){
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn decode(file: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decoder = Decoder::new(Cursor::new(file.to_vec()));
+        decoder.read_signature()?;
+        let header = decoder.read_header()?;
+        let data = decoder.read_data()?;
+        decoder.read_pixels(&header, &data)
+    }
+
+    #[test]
+    fn huge_header_without_data_does_not_allocate() {
+        let file = b"P7\nWIDTH 100000\nHEIGHT 100000\nDEPTH 4\nMAXVAL 65535\nTUPLTYPE RGB_ALPHA\nENDHDR\n";
+        match decode(file) {
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => { },
+            other => panic!("{:?}", other.map(|pixels| pixels.len())),
+        }
+    }
+
+    #[test]
+    fn crlf_after_endhdr() {
+        let file = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE\nENDHDR\r\n\x00\x01";
+        assert_eq!(decode(file).unwrap(), vec![0, 255]);
+    }
+
+    #[test]
+    fn raster_may_start_with_a_line_break() {
+        let file = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\r\n";
+        assert_eq!(decode(file).unwrap(), vec![0x0d, 0x0a]);
+
+        let file = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\n\r";
+        assert_eq!(decode(file).unwrap(), vec![0x0a, 0x0d]);
+    }
+
+    fn decode_lenient(file: &[u8]) -> Result<(Header, Vec<u8>), Error> {
+        let mut decoder = Decoder::new(Cursor::new(file.to_vec()));
+        decoder.set_strict(false);
+        decoder.read_signature()?;
+        let header = decoder.read_header()?;
+        let data = decoder.read_data()?;
+        Ok((header, decoder.read_pixels(&header, &data)?))
+    }
+
+    #[test]
+    fn lenient_header_with_trailing_blanks() {
+        let file = b"P7\nWIDTH 2  \nHEIGHT\t1\t\ndepth 1 \nMAXVAL 255\nTUPLTYPE GRAYSCALE \nENDHDR \t\n\x20\x09";
+        let (header, pixels) = decode_lenient(file).unwrap();
+        assert_eq!((header.width, header.height, header.color), (2, 1, Color::Grayscale));
+        assert_eq!(pixels, vec![0x20, 0x09]);
+
+        let file = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR  \r\n\r\n";
+        assert_eq!(decode_lenient(file).unwrap().1, vec![0x0d, 0x0a]);
+    }
+
+    #[test]
+    fn lenient_blanks_never_reach_into_the_raster() {
+        // LF/CR and a lone CR end the header, the blanks after them are pixels
+        let file = b"P7\nWIDTH 3\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\r \t";
+        assert_eq!(decode_lenient(file).unwrap().1, vec![0x0d, 0x20, 0x09]);
+
+        let file = b"P7\nWIDTH 3\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\r \r\n";
+        assert_eq!(decode_lenient(file).unwrap().1, vec![0x20, 0x0d, 0x0a]);
+    }
+
+    #[test]
+    fn implicit_end_of_header_is_lenient_only() {
+        let file = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nAB";
+        let (header, pixels) = decode_lenient(file).unwrap();
+        assert_eq!(header.maxval, 255);
+        assert_eq!(pixels, b"AB".to_vec());
+
+        match decode(file) {
+            Err(Error::InvalidHeader) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn three_images_in_one_stream() {
+        let frames = [
+            (Header { width: 2, height: 1, depth: 1, maxval: 255, color: Color::Grayscale }, vec![7u8, 9]),
+            (Header { width: 1, height: 1, depth: 3, maxval: 65535, color: Color::RGB }, vec![0u8, 1, 2, 3, 4, 5]),
+            (Header { width: 1, height: 2, depth: 2, maxval: 1, color: Color::BlackAndWhiteAlpha }, vec![1u8, 0, 0, 1]),
+        ];
+
+        let mut stream: Vec<u8> = Vec::new();
+        for &(ref header, ref pixels) in frames.iter() {
+            write_image(&mut stream, header, pixels).unwrap();
+        }
+
+        let mut decoder = Decoder::new(Cursor::new(stream));
+        let mut count = 0;
+
+        loop {
+            assert_eq!(decoder.read_signature().unwrap(), PAM_BINARY_MAGIC_NUMBER);
+            let header = decoder.read_header().unwrap();
+            let data = decoder.read_data().unwrap();
+
+            assert_eq!(header, frames[count].0);
+            assert_eq!(decoder.read_raw_pixels(&data).unwrap(), frames[count].1);
+            count += 1;
+
+            if !decoder.next_image().unwrap() {
+                break;
+            }
+        }
+
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn write_image_checks_the_header_against_the_pixels() {
+        let header = Header { width: 2, height: 2, depth: 1, maxval: 255, color: Color::Grayscale };
+        match write_image(&mut Vec::new(), &header, &[0, 0, 0]) {
+            Err(Error::InvalidImageData) => { },
+            other => panic!("{:?}", other),
+        }
+
+        let header = Header { width: 1, height: 1, depth: 1, maxval: 255, color: Color::BlackAndWhite };
+        match write_image(&mut Vec::new(), &header, &[0]) {
+            Err(Error::InvalidHeader) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn color_and_depth_descriptions() {
+        let header = Header { width: 1, height: 1, depth: 1, maxval: 65535, color: Color::Grayscale };
+        assert_eq!(header.color_and_depth_description(), "16-bit Grayscale");
+
+        let header = Header { width: 1, height: 1, depth: 2, maxval: 1, color: Color::BlackAndWhiteAlpha };
+        assert_eq!(header.color_and_depth_description(), "1-bit Black and White Alpha");
+
+        let header = Header { width: 1, height: 1, depth: 4, maxval: 255, color: Color::RGBA };
+        assert_eq!(header.color_and_depth_description(), "8-bit RGBA");
+    }
+
+    #[test]
+    fn header_comments_run_to_the_end_of_the_line() {
+        let file = b"P7\n# written by some tool\nWIDTH 2\nHEIGHT 1\n#DEPTH 3\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x01\x02";
+        assert_eq!(decode(file).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn image_info_from_the_header() {
+        let header = Header { width: 4, height: 3, depth: 4, maxval: 65535, color: Color::RGBA };
+        assert_eq!(ImageInfo::from(header), ImageInfo {
+            width: 4,
+            height: 3,
+            bit_depth: 16,
+            channels: 4,
+            has_alpha: true,
+            color_space: ColorSpace::Rgba,
+        });
+
+        let header = Header { width: 1, height: 1, depth: 2, maxval: 1, color: Color::BlackAndWhiteAlpha };
+        let info = ImageInfo::from(header);
+        assert_eq!((info.bit_depth, info.channels, info.has_alpha, info.color_space), (1, 2, true, ColorSpace::GrayAlpha));
+    }
+
+    #[test]
+    fn black_and_white_needs_maxval_1() {
+        let file = b"P7\nWIDTH 3\nHEIGHT 1\nDEPTH 1\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE\nENDHDR\n\x00\x01\x01";
+        assert_eq!(decode(file).unwrap(), vec![0, 255, 255]);
+
+        let file = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE_ALPHA\nENDHDR\n\x01\x00";
+        assert_eq!(decode(file).unwrap(), vec![255, 0]);
+
+        for file in [&b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE BLACKANDWHITE\nENDHDR\n\x01"[..],
+                     &b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 2\nTUPLTYPE BLACKANDWHITE_ALPHA\nENDHDR\n\x01\x01"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other),
+            }
+        }
+
+        // a sample above maxval
+        let file = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE\nENDHDR\n\x02";
+        match decode(file) {
+            Err(Error::InvalidImageData) => { },
+            other => panic!("{:?}", other),
+        }
+
+        // grayscale may use the full 16-bit range
+        let file = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 65535\nTUPLTYPE GRAYSCALE\nENDHDR\n\xff\xfe";
+        assert_eq!(decode(file).unwrap(), vec![0xff, 0xfe]);
+    }
+
+    #[test]
+    fn pixels_size_of_deep_and_empty_images() {
+        // 200 planes of two bytes, 400 would overflow a u8
+        let mut decoder = Decoder::new(Cursor::new(b"P7\nWIDTH 3\nHEIGHT 2\nDEPTH 200\nMAXVAL 65535\nTUPLTYPE GRAYSCALE\nENDHDR\n".to_vec()));
+        decoder.read_signature().unwrap();
+        decoder.read_header().unwrap();
+        assert_eq!(decoder.read_data().unwrap().length, 3 * 2 * 200 * 2);
+
+        for file in [&b"P7\nWIDTH 0\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n"[..],
+                     &b"P7\nWIDTH 1\nHEIGHT 0\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n"[..],
+                     &b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 0\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn read_header_only_stops_at_the_pixels() {
+        let file = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\r".to_vec();
+        let mut handle = Cursor::new(file.clone());
+
+        let header = read_header_only(&mut handle).unwrap();
+        assert_eq!(header, Header { width: 1, height: 1, depth: 1, maxval: 255, color: Color::Grayscale });
+        assert_eq!(handle.position(), file.len() as u64 - 1);
+    }
+
+    #[test]
+    fn size_hint_counts_the_elements_left() {
+        let file = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x07".to_vec();
+        let mut decoder = Decoder::new(Cursor::new(file));
+
+        for remaining in (0..4).rev() {
+            assert_eq!(decoder.size_hint(), (remaining, Some(remaining)));
+            if remaining > 0 {
+                assert!(decoder.next().is_some());
+            }
+        }
+        assert!(decoder.next().is_none());
+    }
+}
@@ -0,0 +1,444 @@
+#![feature(try_from, const_fn, duration_as_u128, nll)]
+#![allow(unused_variables, unused_imports, unused_mut)]
+
+// http://netpbm.sourceforge.net/doc/pfm.html
+
+extern crate byteorder;
+
+mod netpbm;
+mod info;
+
+pub use self::netpbm::{ PFM_GRAYSCALE_MAGIC_NUMBER, PFM_RGB_MAGIC_NUMBER, Lines };
+use self::netpbm::read_raster;
+pub use self::info::{ Origin, PixelLayout };
+
+use byteorder::{ ByteOrder, BigEndian, LittleEndian };
+
+use std::io;
+use std::fmt;
+use std::mem;
+use std::cmp;
+use std::str;
+use std::thread;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use std::fs::{ File, OpenOptions };
+use std::time::{ Duration, Instant };
+use std::io::{ Read, Write, Seek, SeekFrom };
+
+
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    InvalidSignature,
+    InvalidHeader,
+    InvalidImageData,
+    Other(&'static str),
+}
+
+impl From<io::Error> for Error {
+    fn from(ioerr: io::Error) -> Error {
+        Error::IoError(ioerr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub width: u64,
+    pub height: u64,
+    /// 1 for `Pf`, 3 for `PF`
+    pub channels: u8,
+    /// absolute value of the scale factor line
+    pub scale: f32,
+    /// a negative scale factor means little-endian samples
+    pub endian: Endian,
+}
+
+impl Header {
+    /// Layout of the raster: bottom-left origin, rows of `channels` 4-byte floats per pixel.
+    pub fn pixel_layout(&self) -> PixelLayout {
+        PixelLayout {
+            origin: Origin::BottomLeft,
+            row_stride: (self.width * self.channels as u64 * 4) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Data {
+    pub offset: u64,
+    pub length: u64,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Pending,
+    Signature,
+    Header,
+    Data,
+}
+
+#[derive(Clone)]
+pub struct Decoder<RS: Read + Seek> {
+    state: State,
+    line_reader: Lines<RS>,
+    signature: Option<[u8; 2]>,
+    channels: u8,
+    pixels_size: u64,
+}
+
+impl<RS: Read + Seek> Decoder<RS> {
+
+    pub fn new(handle: RS) -> Self {
+        Decoder {
+            state: State::Pending,
+            line_reader: Lines { handle: handle },
+            signature: None,
+            channels: 0,
+            pixels_size: 0,
+        }
+    }
+
+    /// Gives back the underlying handle, e.g. after iterating with `for elem in &mut decoder`.
+    pub fn into_inner(self) -> RS {
+        self.line_reader.into_handle()
+    }
+
+    /// Reads the signature from the current position of the handle, the decoder does not
+    /// rewind. Once read the signature is cached and later calls return it without reading.
+    pub fn read_signature(&mut self) -> Result<[u8; 2], Error> {
+        if let Some(signature) = self.signature {
+            return Ok(signature);
+        }
+
+        assert_eq!(self.state, State::Pending);
+
+        if let Some(line) = self.line_reader.next() {
+            if line.len() == 2 {
+                let signature = [ line[0], line[1], ];
+
+                self.channels = match signature {
+                    PFM_GRAYSCALE_MAGIC_NUMBER => 1,
+                    PFM_RGB_MAGIC_NUMBER => 3,
+                    _ => return Err(Error::InvalidSignature),
+                };
+                self.signature = Some(signature);
+                self.state = State::Signature;
+
+                return Ok(signature)
+            }
+        }
+
+        Err(Error::InvalidSignature)
+    }
+
+    /// Rewinds the handle to the start of the file and forgets everything read so far.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.line_reader.handle.seek(SeekFrom::Start(0))?;
+
+        self.state = State::Pending;
+        self.signature = None;
+        self.channels = 0;
+        self.pixels_size = 0;
+
+        Ok(())
+    }
+
+    fn next_value(&mut self) -> Option<String> {
+        if let Some(line) = self.line_reader.next() {
+            if line.len() > 0 {
+                if let Ok(s) = String::from_utf8(line) {
+                    if s.is_ascii() {
+                        return Some(s)
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    pub fn read_header(&mut self) -> Result<Header, Error> {
+        assert_eq!(self.state, State::Signature);
+
+        let width: u64 = {
+            match self.next_value() {
+                Some(val) => {
+                    if let Ok(v) = val.parse::<u64>() {
+                        v
+                    } else {
+                        return Err(Error::InvalidHeader);
+                    }
+                },
+                None => return Err(Error::InvalidHeader),
+            }
+        };
+
+        let height: u64 = {
+            match self.next_value() {
+                Some(val) => {
+                    if let Ok(v) = val.parse::<u64>() {
+                        v
+                    } else {
+                        return Err(Error::InvalidHeader);
+                    }
+                },
+                None => return Err(Error::InvalidHeader),
+            }
+        };
+
+        let scale: f32 = {
+            match self.next_value() {
+                Some(val) => {
+                    if let Ok(v) = val.parse::<f32>() {
+                        v
+                    } else {
+                        return Err(Error::InvalidHeader);
+                    }
+                },
+                None => return Err(Error::InvalidHeader),
+            }
+        };
+
+        if width == 0 || height == 0 || scale == 0.0 || !scale.is_finite() {
+            return Err(Error::InvalidHeader);
+        }
+
+        // Exactly one whitespace byte separates the scale from the raster.
+        self.line_reader.raster_start().map_err(|_| Error::InvalidHeader)?;
+
+        let header = Header {
+            width: width,
+            height: height,
+            channels: self.channels,
+            scale: scale.abs(),
+            endian: if scale < 0.0 { Endian::Little } else { Endian::Big },
+        };
+
+        // 4 bytes per sample
+        let pixels_size = header.width.checked_mul(header.height)
+                                      .and_then(|n| n.checked_mul(header.channels as u64 * 4));
+
+        match pixels_size {
+            Some(pixels_size) => self.pixels_size = pixels_size,
+            None => return Err(Error::InvalidHeader),
+        }
+
+        self.state = State::Header;
+
+        Ok(header)
+    }
+
+    pub fn read_data(&mut self) -> Result<Data, Error> {
+        assert_eq!(self.state, State::Header);
+
+        let pos = self.line_reader.position()?;
+
+        self.state = State::Data;
+
+        Ok(Data {
+            offset: pos,
+            length: self.pixels_size,
+        })
+    }
+
+    /// Reads the raster as floats in the declared byte order.
+    ///
+    /// Samples are in file order: rows run from the bottom of the image to the top,
+    /// each row left to right with `header.channels` samples per pixel.
+    pub fn samples_f32(&mut self, header: &Header, data: &Data) -> Result<Vec<f32>, Error> {
+        self.line_reader.handle.seek(SeekFrom::Start(data.offset))?;
+        let bytes = read_raster(&mut self.line_reader.handle, data.length)?;
+
+        let samples = bytes.chunks(4)
+                           .map(|sample| match header.endian {
+                                Endian::Little => LittleEndian::read_f32(sample),
+                                Endian::Big => BigEndian::read_f32(sample),
+                           })
+                           .collect::<Vec<f32>>();
+
+        Ok(samples)
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Element {
+    Signature([u8; 2]),
+    Header(Header),
+    Data(Data),
+}
+
+impl Element {
+
+    pub fn is_signature(&self) -> bool {
+        match *self {
+            Element::Signature(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_header(&self) -> bool {
+        match *self {
+            Element::Header(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_data(&self) -> bool {
+        match *self {
+            Element::Data(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn signature(&self) -> [u8; 2] {
+        match *self {
+            Element::Signature(signature) => signature,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn header(&self) -> Header {
+        match *self {
+            Element::Header(header) => header,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn data(&self) -> Data {
+        match *self {
+            Element::Data(data) => data,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<Handle: Read + Seek> Iterator for Decoder<Handle> {
+    type Item = Element;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.state == State::Pending {
+            if let Ok(signature) = self.read_signature() {
+                Some(Element::Signature(signature))
+            } else {
+                None
+            }
+        } else if self.state == State::Signature {
+            if let Ok(header) = self.read_header() {
+                Some(Element::Header(header))
+            } else {
+                None
+            }
+        } else if self.state == State::Header {
+            if let Ok(data) = self.read_data() {
+                Some(Element::Data(data))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Counts the elements left of signature, header and data, fewer only if the file is malformed.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = match self.state {
+            State::Pending => 3,
+            State::Signature => 2,
+            State::Header => 1,
+            State::Data => 0,
+        };
+
+        (remaining, Some(remaining))
+    }
+}
+
+
+fn main(){
+    let filepath = "output.pfm";
+    let file = File::open(filepath).unwrap();
+    let mut decoder = Decoder::new(file);
+
+    let mut header: Option<Header> = None;
+    let mut data: Option<Data> = None;
+
+    for elem in &mut decoder {
+        match elem {
+            Element::Signature(signature) => {
+                println!("Signature: {:?}", signature);
+            },
+            Element::Header(_header) => {
+                println!("{:?}", _header);
+                header = Some(_header);
+            },
+            Element::Data(_data) => {
+                println!("{:?}", _data);
+                data = Some(_data);
+            },
+        }
+    }
+
+    if let (Some(header), Some(data)) = (header, data) {
+        let samples = decoder.samples_f32(&header, &data).unwrap();
+        println!("{:?}", &samples[..cmp::min(samples.len(), 12)]);
+        println!("Samples: {:?}", samples.len());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+
+    fn decode(file: &[u8]) -> Result<Vec<f32>, Error> {
+        let mut decoder = Decoder::new(Cursor::new(file.to_vec()));
+        decoder.read_signature()?;
+        let header = decoder.read_header()?;
+        let data = decoder.read_data()?;
+        decoder.samples_f32(&header, &data)
+    }
+
+    #[test]
+    fn zero_dimensions_are_rejected() {
+        for file in [&b"PF\n0 1\n-1.0\n"[..], &b"Pf\n1 0\n1.0\n"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other),
+            }
+        }
+
+        // iterating stops instead of panicking
+        let decoder = Decoder::new(Cursor::new(b"PF\n0 1\n-1.0\n".to_vec()));
+        assert_eq!(decoder.count(), 1);
+    }
+
+    #[test]
+    fn huge_header_without_data_does_not_allocate() {
+        match decode(b"PF\n100000 100000\n-1.0\n") {
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => { },
+            other => panic!("{:?}", other.map(|samples| samples.len())),
+        }
+    }
+
+    #[test]
+    fn samples_in_declared_byte_order() {
+        let mut file = b"Pf\n2 1\n-1.0\n".to_vec();
+        file.write_f32::<LittleEndian>(0.5).unwrap();
+        file.write_f32::<LittleEndian>(-2.0).unwrap();
+        assert_eq!(decode(&file).unwrap(), vec![0.5, -2.0]);
+
+        let mut file = b"Pf\n1 1\n1.0\n".to_vec();
+        file.write_f32::<BigEndian>(1.25).unwrap();
+        assert_eq!(decode(&file).unwrap(), vec![1.25]);
+    }
+}
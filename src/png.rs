@@ -6,7 +6,12 @@ extern crate flate2;
 extern crate byteorder;
 extern crate num_cpus;
 
-use byteorder::{NetworkEndian, ReadBytesExt};
+mod info;
+
+pub use self::info::{ ImageInfo, ColorSpace, Origin, PixelLayout };
+
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crc::{crc32, Hasher32};
 
 
 use std::io;
@@ -14,6 +19,9 @@ use std::mem;
 use std::cmp;
 use std::thread;
 use std::convert::TryFrom;
+use std::sync::Arc;
+use std::path::Path;
+use std::collections::HashMap;
 use std::fs::{ File, OpenOptions };
 use std::time::{ Duration, Instant };
 use std::io::{ Read, Write, Seek, SeekFrom };
@@ -53,6 +61,10 @@ pub enum Error {
     },
     Other(&'static str),
     CorruptFlateStream,
+    /// The file ended inside a chunk, `chunk_kind` is `None` if it ended before the type.
+    UnexpectedEof {
+        chunk_kind: Option<ChunkKind>,
+    },
 }
 
 impl From<io::Error> for Error {
@@ -61,6 +73,15 @@ impl From<io::Error> for Error {
     }
 }
 
+/// Recoverable problems recorded by the decoder in lenient mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// A critical chunk that may only appear once was seen again, the later copy was skipped.
+    DuplicateChunk(ChunkKind),
+    /// The signature was found after this many junk bytes, e.g. a UTF-8 BOM.
+    JunkBeforeSignature(usize),
+}
+
 
 
 pub const VERSION: &'static str = "2.0";
@@ -68,6 +89,14 @@ pub const VERSION: &'static str = "2.0";
 /// [PNG file signature](http://www.w3.org/TR/PNG/#5PNG-file-signature)
 pub const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
+const CHUNK_BUFFER_SIZE: usize = 4 * 1024;
+/// How many leading junk bytes lenient mode scans past looking for the signature.
+const SIGNATURE_SCAN_LIMIT: usize = 16;
+/// Default cap on the inflated size of a single text chunk.
+const DEFAULT_MAX_TEXT_SIZE: usize = 16 * 1024 * 1024;
+/// Default cap on width * height, 4 GiB once expanded to 8-bit RGBA.
+const DEFAULT_MAX_PIXELS: u64 = 1 << 30;
+
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -135,6 +164,46 @@ impl Color {
             TruecolourWithAlpha => 4
         }
     }
+
+    pub fn has_alpha(&self) -> bool {
+        match *self {
+            Color::GreyscaleWithAlpha | Color::TruecolourWithAlpha => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_grayscale(&self) -> bool {
+        match *self {
+            Color::Greyscale | Color::GreyscaleWithAlpha => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        *self == Color::Indexed
+    }
+
+    /// Whether the spec allows `depth` for this color type.
+    /// https://www.w3.org/TR/PNG/#11IHDR
+    ///
+    /// | Color type                | Allowed bit depths |
+    /// |---------------------------|--------------------|
+    /// | Greyscale (0)             | 1, 2, 4, 8, 16     |
+    /// | Truecolour (2)            | 8, 16              |
+    /// | Indexed (3)               | 1, 2, 4, 8         |
+    /// | GreyscaleWithAlpha (4)    | 8, 16              |
+    /// | TruecolourWithAlpha (6)   | 8, 16              |
+    pub fn is_valid_bitdepth(&self, depth: BitDepth) -> bool {
+        use self::BitDepth::*;
+
+        match *self {
+            Color::Greyscale => true,
+            Color::Indexed => depth != Sixteen,
+            Color::Truecolour | Color::GreyscaleWithAlpha | Color::TruecolourWithAlpha => {
+                depth == Eight || depth == Sixteen
+            },
+        }
+    }
 }
 
 /// Bit depth of the png file
@@ -190,10 +259,193 @@ impl Into<u8> for BitDepth {
     }
 }
 
+/// Compression method of the png file, the spec only defines one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionMethod {
+    /// zlib stream with a deflate window of at most 32 KiB
+    Deflate = 0,
+}
+
+impl<'a> TryFrom<&'a u8> for CompressionMethod {
+    type Error = ();
+
+    fn try_from(n: &u8) -> Result<CompressionMethod, Self::Error> {
+        match *n {
+            0 => Ok(CompressionMethod::Deflate),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'a> Into<u8> for &'a CompressionMethod {
+    #[inline]
+    fn into(self) -> u8 {
+        match *self {
+            CompressionMethod::Deflate => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for CompressionMethod {
+    type Error = ();
+
+    fn try_from(n: u8) -> Result<CompressionMethod, Self::Error> {
+        CompressionMethod::try_from(&n)
+    }
+}
+
+impl Into<u8> for CompressionMethod {
+    fn into(self) -> u8 {
+        (&self).into()
+    }
+}
+
+/// Filter method of the png file, the spec only defines one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FilterMethod {
+    /// per-scanline choice of the five basic filter types
+    Adaptive = 0,
+}
+
+impl<'a> TryFrom<&'a u8> for FilterMethod {
+    type Error = ();
+
+    fn try_from(n: &u8) -> Result<FilterMethod, Self::Error> {
+        match *n {
+            0 => Ok(FilterMethod::Adaptive),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'a> Into<u8> for &'a FilterMethod {
+    #[inline]
+    fn into(self) -> u8 {
+        match *self {
+            FilterMethod::Adaptive => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for FilterMethod {
+    type Error = ();
+
+    fn try_from(n: u8) -> Result<FilterMethod, Self::Error> {
+        FilterMethod::try_from(&n)
+    }
+}
+
+impl Into<u8> for FilterMethod {
+    fn into(self) -> u8 {
+        (&self).into()
+    }
+}
+
+/// Interlace method of the png file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Interlace {
+    None  = 0,
+    /// seven passes over a repeating 8x8 pattern, see `deinterlace`
+    Adam7 = 1,
+}
+
+impl<'a> TryFrom<&'a u8> for Interlace {
+    type Error = ();
+
+    fn try_from(n: &u8) -> Result<Interlace, Self::Error> {
+        match *n {
+            0 => Ok(Interlace::None),
+            1 => Ok(Interlace::Adam7),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'a> Into<u8> for &'a Interlace {
+    #[inline]
+    fn into(self) -> u8 {
+        match *self {
+            Interlace::None => 0,
+            Interlace::Adam7 => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for Interlace {
+    type Error = ();
+
+    fn try_from(n: u8) -> Result<Interlace, Self::Error> {
+        Interlace::try_from(&n)
+    }
+}
+
+impl Into<u8> for Interlace {
+    fn into(self) -> u8 {
+        (&self).into()
+    }
+}
+
+
+// https://www.w3.org/TR/PNG/#9Filter-types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FilterType {
+    None    = 0,
+    Sub     = 1,
+    Up      = 2,
+    Average = 3,
+    Paeth   = 4,
+}
+
+impl<'a> TryFrom<&'a u8> for FilterType {
+    type Error = ();
+
+    fn try_from(n: &u8) -> Result<FilterType, Self::Error> {
+        match *n {
+            0 => Ok(FilterType::None),
+            1 => Ok(FilterType::Sub),
+            2 => Ok(FilterType::Up),
+            3 => Ok(FilterType::Average),
+            4 => Ok(FilterType::Paeth),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'a> Into<u8> for &'a FilterType {
+    #[inline]
+    fn into(self) -> u8 {
+        match *self {
+            FilterType::None => 0,
+            FilterType::Sub => 1,
+            FilterType::Up => 2,
+            FilterType::Average => 3,
+            FilterType::Paeth => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for FilterType {
+    type Error = ();
+
+    fn try_from(n: u8) -> Result<FilterType, Self::Error> {
+        FilterType::try_from(&n)
+    }
+}
+
+impl Into<u8> for FilterType {
+    fn into(self) -> u8 {
+        (&self).into()
+    }
+}
+
 
 // https://www.w3.org/TR/PNG/#4Concepts.FormatTypes
 #[allow(non_upper_case_globals, non_camel_case_types)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum ChunkKind {
     // -- Critical chunks --
@@ -243,14 +495,29 @@ pub enum ChunkKind {
     // Time information
     /// Image last-modification time
     tIME,
+
+    // -- Registered extension chunks --
+    /// Stereo image indicator
+    sTER,
+    /// Fractal image parameters, no public specification
+    fRAc,
+    /// GIF Graphic Control Extension
+    gIFg,
+    /// GIF Application Extension
+    gIFx,
+
+    // -- Non-standard chunks --
+    /// Apple's iOS-optimized PNG marker, precedes IHDR.
+    /// The image data is BGRA, alpha-premultiplied and a raw deflate stream without zlib header.
+    CgBI,
     
-    // // -- Extension chunks --
-    // /// Animation control
-    // acTL,
-    // /// Frame control
-    // fcTL,
-    // /// Frame data
-    // fdAT,
+    // -- Extension chunks --
+    /// Animation control
+    acTL,
+    /// Frame control
+    fcTL,
+    /// Frame data
+    fdAT,
 }
 
 impl<'a> TryFrom<&'a [u8]> for ChunkKind {
@@ -284,9 +551,16 @@ impl<'a> TryFrom<&'a [u8]> for ChunkKind {
             
             b"tIME" => Ok(ChunkKind::tIME),
 
-            // b"acTL" => Ok(ChunkKind::acTL),
-            // b"fcTL" => Ok(ChunkKind::fcTL),
-            // b"fdAT" => Ok(ChunkKind::fdAT),
+            b"sTER" => Ok(ChunkKind::sTER),
+            b"fRAc" => Ok(ChunkKind::fRAc),
+            b"gIFg" => Ok(ChunkKind::gIFg),
+            b"gIFx" => Ok(ChunkKind::gIFx),
+
+            b"CgBI" => Ok(ChunkKind::CgBI),
+
+            b"acTL" => Ok(ChunkKind::acTL),
+            b"fcTL" => Ok(ChunkKind::fcTL),
+            b"fdAT" => Ok(ChunkKind::fdAT),
             _ => Err(()),
         }
     }
@@ -308,6 +582,19 @@ impl<'a> TryFrom<&'a [u8; 4]> for ChunkKind {
     }
 }
 
+impl<'a> TryFrom<&'a str> for ChunkKind {
+    type Error = ();
+
+    /// Parses a four letter chunk type such as `"tEXt"`, case matters.
+    fn try_from(kind: &str) -> Result<ChunkKind, Self::Error> {
+        if kind.len() != 4 || !kind.is_ascii() {
+            return Err(());
+        }
+
+        ChunkKind::try_from(kind.as_bytes())
+    }
+}
+
 impl<'a> Into<&'static [u8; 4]> for &'a ChunkKind {
     #[inline]
     fn into(self) -> &'static [u8; 4] {
@@ -335,9 +622,16 @@ impl<'a> Into<&'static [u8; 4]> for &'a ChunkKind {
             
             ChunkKind::tIME => b"tIME",
 
-            // ChunkKind::acTL => b"acTL",
-            // ChunkKind::fcTL => b"fcTL",
-            // ChunkKind::fdAT => b"fdAT",
+            ChunkKind::sTER => b"sTER",
+            ChunkKind::fRAc => b"fRAc",
+            ChunkKind::gIFg => b"gIFg",
+            ChunkKind::gIFx => b"gIFx",
+
+            ChunkKind::CgBI => b"CgBI",
+
+            ChunkKind::acTL => b"acTL",
+            ChunkKind::fcTL => b"fcTL",
+            ChunkKind::fdAT => b"fdAT",
         }
     }
 }
@@ -421,25 +715,229 @@ pub struct Chunk {
     pub offset: u64,
 }
 
+impl Chunk {
+    pub fn is_of_kind(&self, kind: ChunkKind) -> bool {
+        self.kind == kind
+    }
+}
+
+/// Start offset and length of the file region spanning the data of all IDAT chunks.
+///
+/// IDAT chunks must be consecutive, so the region runs from the first byte of the first
+/// IDAT's data to the last byte of the last one and includes the length, type and CRC
+/// fields between them. Returns `None` if there is no IDAT chunk.
+pub fn idat_byte_range(chunks: &[Chunk]) -> Option<(u64, u64)> {
+    let mut idats = chunks.iter().filter(|chunk| chunk.is_of_kind(ChunkKind::IDAT));
 
+    let first = idats.next()?;
+    let last = idats.next_back().unwrap_or(first);
 
+    Some((first.offset, last.offset + last.length as u64 - first.offset))
+}
+
+
+
+
+
+/// Subimage arrangement of a stereo pair, from the `sTER` chunk.
+/// http://www.libpng.org/pub/png/spec/register/pngext-1.4.0-pdg.html#C.sTER
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// right-eye image on the left, for cross-eyed viewing
+    CrossFuse,
+    /// left-eye image on the left, for wall-eyed viewing
+    DivergingFuse,
+}
+
+/// Fields of the GIF Graphic Control Extension, from the `gIFg` chunk.
+/// http://www.libpng.org/pub/png/spec/register/pngext-1.4.0-pdg.html#C.gIFg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GifGraphicControl {
+    pub disposal_method: u8,
+    pub user_input: bool,
+    /// in hundredths of a second
+    pub delay_time: u16,
+}
+
+/// Contents of the APNG `acTL` chunk.
+/// https://wiki.mozilla.org/APNG_Specification#.60acTL.60:_The_Animation_Control_Chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationControl {
+    pub num_frames: u32,
+    /// 0 means loop forever
+    pub num_plays: u32,
+}
+
+/// RGB entries of the `PLTE` chunk, indexed by the pixel values of an indexed image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette(pub Vec<[u8; 3]>);
+
+impl Palette {
+    pub fn get(&self, index: u8) -> Option<[u8; 3]> {
+        self.0.get(index as usize).cloned()
+    }
+}
+
+/// A `tEXt`, `zTXt` or `iTXt` chunk with its text decompressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Text {
+    pub keyword: String,
+    /// `iTXt` only, empty when unknown
+    pub language_tag: String,
+    /// `iTXt` only, the keyword in `language_tag`
+    pub translated_keyword: String,
+    pub text: String,
+}
 
 
+/// Metadata-only overview of a file, from `Decoder::summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub header: Header,
+    /// all chunks, IHDR and IEND included
+    pub chunk_count: usize,
+    pub chunk_counts: HashMap<ChunkKind, usize>,
+    /// sum of the IDAT data lengths
+    pub idat_bytes: u64,
+    pub interlaced: bool,
+    /// an `acTL` chunk is present
+    pub animated: bool,
+}
+
+impl Summary {
+    pub fn contains(&self, kind: ChunkKind) -> bool {
+        self.chunk_counts.contains_key(&kind)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Header {
     pub width: u32,
     pub height: u32,
     pub bitdepth: BitDepth,
     pub color: Color,
-    pub compression_method: u8, // 0: deflate/inflate
-    pub filter_method: u8,      // 0: adaptive filtering with five basic filter types
-    pub interlace_method: u8,   // 0: no interlace  1: Adam7 interlace
+    pub compression_method: CompressionMethod,
+    pub filter_method: FilterMethod,
+    pub interlace_method: Interlace,
+}
+
+impl Header {
+    /// Short description for display, e.g. "8-bit RGBA" or "1-bit Indexed".
+    pub fn color_and_depth_description(&self) -> String {
+        let bitdepth: u8 = self.bitdepth.into();
+        let color = match self.color {
+            Color::Greyscale => "Grayscale",
+            Color::Truecolour => "RGB",
+            Color::Indexed => "Indexed",
+            Color::GreyscaleWithAlpha => "Grayscale Alpha",
+            Color::TruecolourWithAlpha => "RGBA",
+        };
+
+        format!("{}-bit {}", bitdepth, color)
+    }
+
+    /// Size in bytes of the image expanded to 8-bit RGBA.
+    ///
+    /// The multiplication is checked, a 65535x65535 image does not fit in
+    /// `usize` on 32-bit targets and must not wrap around to a small buffer.
+    pub fn rgba8_size(&self) -> Result<usize, Error> {
+        (self.width as usize).checked_mul(self.height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .ok_or(Error::Format("image too large"))
+    }
+
+    /// Bytes per complete pixel rounded up to one, the distance the
+    /// Sub, Average and Paeth filters look back.
+    pub fn bytes_per_pixel(&self) -> usize {
+        let bitdepth: u8 = self.bitdepth.into();
+        cmp::max(1, self.color.samples() * bitdepth as usize / 8)
+    }
+
+    /// Bytes in a scanline of `width` pixels, without the leading filter type byte.
+    pub fn scanline_size(&self, width: u32) -> usize {
+        self.scanline_bytes(width) as usize
+    }
+
+    // a full-width 16-bit RGBA scanline is up to 16 GiB, more than a 32-bit usize holds
+    fn scanline_bytes(&self, width: u32) -> u64 {
+        let bitdepth: u8 = self.bitdepth.into();
+        let bits = width as u64 * self.color.samples() as u64 * bitdepth as u64;
+        (bits + 7) / 8
+    }
+
+    /// Size of the inflated image data: every scanline with its filter type byte,
+    /// of all seven passes for an interlaced image.
+    pub fn filtered_size(&self) -> Result<usize, Error> {
+        let passes: Vec<(u32, u32)> = if self.interlace_method == Interlace::Adam7 {
+            adam7_pass_dimensions(self.width, self.height).to_vec()
+        } else {
+            vec![(self.width, self.height)]
+        };
+
+        passes.iter()
+              .filter(|&&(width, _)| width > 0)
+              .fold(Some(0u64), |size, &(width, height)| {
+                  (self.scanline_bytes(width) + 1).checked_mul(height as u64)
+                                                  .and_then(|pass| size.and_then(|size| size.checked_add(pass)))
+              })
+              .filter(|&size| size <= usize::max_value() as u64)
+              .map(|size| size as usize)
+              .ok_or(Error::Format("image too large"))
+    }
+
+    /// Layout of the buffers `unfilter` and `deinterlace` return: top-left origin,
+    /// tightly packed scanlines.
+    pub fn pixel_layout(&self) -> PixelLayout {
+        PixelLayout {
+            origin: Origin::TopLeft,
+            row_stride: self.scanline_size(self.width),
+        }
+    }
+}
+
+impl From<Header> for ImageInfo {
+    fn from(header: Header) -> ImageInfo {
+        let bitdepth: u8 = header.bitdepth.into();
+        let color_space = match header.color {
+            Color::Greyscale => ColorSpace::Gray,
+            Color::GreyscaleWithAlpha => ColorSpace::GrayAlpha,
+            Color::Truecolour => ColorSpace::Rgb,
+            Color::TruecolourWithAlpha => ColorSpace::Rgba,
+            Color::Indexed => ColorSpace::Indexed,
+        };
+
+        ImageInfo {
+            width: header.width as u64,
+            height: header.height as u64,
+            bit_depth: bitdepth,
+            channels: header.color.samples() as u8,
+            has_alpha: header.color.has_alpha(),
+            color_space: color_space,
+        }
+    }
 }
 
+/// Cloning a decoder snapshots its state and clones the handle. The two continue
+/// independently as long as the cloned handles keep separate read positions (e.g. `Cursor`).
+#[derive(Clone)]
 pub struct Decoder<Handle: Read + Seek> {
     state: State,
     handle: Handle,
+    signature: Option<[u8; 8]>,
+    /// offset of the first chunk, right after the signature
+    first_chunk: Option<u64>,
+    header: Option<Header>,
     chunk_index: usize,
+    strict: bool,
+    crc_validation: bool,
+    skip_ancillary: bool,
+    scale_grayscale_to_8bit: bool,
+    max_text_size: usize,
+    max_pixels: u64,
+    warnings: Vec<Warning>,
+    seen_header: bool,
+    seen_palette: bool,
+    cgbi: bool,
 }
 
 impl<Handle: Read + Seek> Decoder<Handle> {
@@ -448,189 +946,3253 @@ impl<Handle: Read + Seek> Decoder<Handle> {
         Decoder {
             state: State::Pending,
             handle: handle,
+            signature: None,
+            first_chunk: None,
+            header: None,
             chunk_index: 0usize,
+            strict: true,
+            crc_validation: true,
+            skip_ancillary: false,
+            scale_grayscale_to_8bit: false,
+            max_text_size: DEFAULT_MAX_TEXT_SIZE,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            warnings: Vec::new(),
+            seen_header: false,
+            seen_palette: false,
+            cgbi: false,
         }
     }
-    
-    pub fn read_signature(&mut self) -> Result<[u8; 8], Error> {
-        let mut signature = [0u8; 8];
-        
-        self.chunk_index = 0usize;
 
-        self.handle.seek(SeekFrom::Start(0)).unwrap();
+    /// In strict mode (the default) malformed chunk sequences are errors,
+    /// in lenient mode the decoder recovers where it can and records a `Warning`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
 
-        match self.handle.read_exact(&mut signature) {
-            Ok(_) => {
-                self.state = State::Signature;
-                Ok(signature)
-            },
-            Err(io_error) => Err(io_error.into())
-        }
+    /// When set (the default), `read_chunk` and chunk iteration compute the CRC of every chunk
+    /// and return `Error::CrcMismatch` if it differs from the stored one. Clearing it seeks
+    /// over the chunk data instead of reading it.
+    pub fn set_crc_validation(&mut self, crc_validation: bool) {
+        self.crc_validation = crc_validation;
     }
 
-    pub fn read_chunk(&mut self) -> Result<Chunk, Error> {
-        let length: u32 = self.handle.read_u32::<NetworkEndian>().unwrap();
+    /// When set, chunk iteration seeks past ancillary chunks without reading their data or
+    /// checking their CRC, unknown ones included. Only the critical chunks plus `tRNS` and
+    /// `bKGD`, which affect how pixels are composed, are returned.
+    pub fn set_skip_ancillary(&mut self, skip_ancillary: bool) {
+        self.skip_ancillary = skip_ancillary;
+    }
 
-        let mut buf = [0u8; 4];
+    /// When set, `for_each_pixel` scales 1, 2 and 4-bit grayscale samples to the full 8-bit
+    /// range (a 2-bit 3 becomes 255) instead of passing the raw values. Palette indices are
+    /// never scaled.
+    pub fn set_scale_grayscale_to_8bit(&mut self, scale_grayscale_to_8bit: bool) {
+        self.scale_grayscale_to_8bit = scale_grayscale_to_8bit;
+    }
 
-        let kind: ChunkKind = {
-            if let Ok(_) = self.handle.read_exact(&mut buf) {
-                if let Ok(chunk_kind) = ChunkKind::try_from(&buf) {
-                    chunk_kind
-                } else {
-                    return Err(Error::InvalidChunk);
-                }
-            } else {
-                return Err(Error::InvalidChunk);
-            }
-        };
+    /// Caps the inflated size of a single `zTXt` or `iTXt` chunk, 16 MiB by default.
+    /// `read_text` fails on larger chunks instead of inflating a text bomb.
+    pub fn set_max_text_size(&mut self, max_text_size: usize) {
+        self.max_text_size = max_text_size;
+    }
 
-        let pos: u64 = self.handle.seek(SeekFrom::Current(0)).unwrap();
+    /// Caps width * height, 2^30 pixels by default. `read_image`, `read_image_forward` and
+    /// `for_each_pixel` fail on larger images before inflating anything.
+    pub fn set_max_pixels(&mut self, max_pixels: u64) {
+        self.max_pixels = max_pixels;
+    }
 
-        self.handle.seek(SeekFrom::Current(length as i64)).unwrap();
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
 
-        self.handle.read_exact(&mut buf).unwrap();
-        let crc: [u8; 4] = buf;
+    /// Whether a `CgBI` chunk was seen, i.e. the file is an Apple iOS-optimized PNG
+    /// whose IDAT must be inflated as raw deflate and whose pixels need `cgbi_to_rgba`.
+    pub fn is_cgbi(&self) -> bool {
+        self.cgbi
+    }
 
-        let chunk = Chunk {
-            index: self.chunk_index,
-            length: length,
-            kind: kind,
-            crc: crc,
-            offset: pos,
-        };
+    /// The IHDR header, cached when the IHDR chunk is read. `None` before that.
+    pub fn header(&self) -> Option<Header> {
+        self.header
+    }
 
-        self.chunk_index += 1;
-        self.state = State::Chunk(kind);
+    pub fn color_type(&self) -> Option<Color> {
+        self.header.map(|header| header.color)
+    }
 
-        Ok(chunk)
+    pub fn bit_depth(&self) -> Option<BitDepth> {
+        self.header.map(|header| header.bitdepth)
     }
-}
 
+    /// Gives back the underlying handle, e.g. after iterating with `for elem in &mut decoder`.
+    pub fn into_inner(self) -> Handle {
+        self.handle
+    }
+    
+    /// Reads the signature from the current position of the handle, the decoder does not
+    /// rewind. Once read the signature is cached and later calls return it without reading.
+    ///
+    /// In lenient mode up to `SIGNATURE_SCAN_LIMIT` junk bytes (a BOM, whitespace) before
+    /// the signature are skipped and reported as a `Warning`.
+    pub fn read_signature(&mut self) -> Result<[u8; 8], Error> {
+        if let Some(signature) = self.signature {
+            return Ok(signature);
+        }
 
+        let mut signature = [0u8; 8];
+        self.handle.read_exact(&mut signature)?;
 
-impl<Handle: Read + Seek> Iterator for Decoder<Handle> {
-    type Item = Element;
+        let mut skipped = 0usize;
+        while signature != SIGNATURE {
+            if self.strict || skipped == SIGNATURE_SCAN_LIMIT {
+                return Err(Error::InvalidSignature);
+            }
 
-    fn next(&mut self) -> Option<Self::Item> {
+            signature.rotate_left(1);
+            self.handle.read_exact(&mut signature[7..])?;
+            skipped += 1;
+        }
+
+        if skipped > 0 {
+            self.warnings.push(Warning::JunkBeforeSignature(skipped));
+        }
+
+        self.first_chunk = Some(self.handle.seek(SeekFrom::Current(0))?);
+        self.signature = Some(signature);
+        self.state = State::Signature;
+
+        Ok(signature)
+    }
+
+    /// Rewinds the handle to the start of the file and forgets everything read so far.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.handle.seek(SeekFrom::Start(0))?;
+
+        self.state = State::Pending;
+        self.signature = None;
+        self.first_chunk = None;
+        self.header = None;
+        self.chunk_index = 0usize;
+        self.warnings.clear();
+        self.seen_header = false;
+        self.seen_palette = false;
+        self.cgbi = false;
+
+        Ok(())
+    }
+
+    /// Moves back to the first chunk, right after the signature wherever in the handle it
+    /// was found, and forgets the chunks read since. Reads the signature if that has not
+    /// happened yet.
+    fn rewind(&mut self) -> Result<(), Error> {
+        let first_chunk = match self.first_chunk {
+            Some(first_chunk) => first_chunk,
+            None => {
+                self.read_signature()?;
+                return Ok(());
+            },
+        };
+
+        self.handle.seek(SeekFrom::Start(first_chunk))?;
+
+        self.state = State::Signature;
+        self.header = None;
+        self.chunk_index = 0usize;
+        self.warnings.retain(|warning| match *warning {
+            Warning::JunkBeforeSignature(_) => true,
+            _ => false,
+        });
+        self.seen_header = false;
+        self.seen_palette = false;
+        self.cgbi = false;
+
+        Ok(())
+    }
+
+    /// Rewinds and reads the chunks in order, CRCs checked as set with `set_crc_validation`,
+    /// until `on_chunk` returns `true` or IEND has been passed to it.
+    fn scan_chunks<F>(&mut self, mut on_chunk: F) -> Result<(), Error>
+        where F: FnMut(&mut Self, &Chunk) -> Result<bool, Error>
+    {
+        self.rewind()?;
+
+        loop {
+            let chunk = self.read_chunk()?;
+
+            if on_chunk(self, &chunk)? || chunk.kind == ChunkKind::IEND {
+                return Ok(());
+            }
+        }
+    }
+
+    /// IDAT chunks of the image, in file order. The decoder is left after IEND.
+    fn idat_chunks(&mut self) -> Result<Vec<Chunk>, Error> {
+        let mut idats: Vec<Chunk> = Vec::new();
+
+        self.scan_chunks(|_, chunk| {
+            if chunk.kind == ChunkKind::IDAT {
+                idats.push(*chunk);
+            }
+            Ok(false)
+        })?;
+
+        Ok(idats)
+    }
+
+    pub fn read_chunk(&mut self) -> Result<Chunk, Error> {
+        self.next_chunk(self.crc_validation, None)
+    }
+
+    /// Reads the next chunk as `read_chunk` does, together with its data. The data is read
+    /// once and the CRC checked on those bytes, the handle only moves forward.
+    pub fn read_chunk_with_data(&mut self) -> Result<(Chunk, Vec<u8>), Error> {
+        let mut data: Vec<u8> = Vec::new();
+        let chunk = self.next_chunk(self.crc_validation, Some(&mut data))?;
+
+        Ok((chunk, data))
+    }
+
+    /// Reads the remaining chunks up to IEND, calling `on_chunk` for each one as it is read,
+    /// e.g. to show progress. Returns how many chunks were read.
+    pub fn read_chunks_with<F: FnMut(&Chunk)>(&mut self, mut on_chunk: F) -> Result<usize, Error> {
         if self.state == State::Pending {
-            if let Ok(signature) = self.read_signature() {
-                Some(Element::Signature(signature))
+            self.read_signature()?;
+        }
+
+        let mut count = 0usize;
+
+        while self.state != State::Chunk(ChunkKind::IEND) {
+            let chunk = self.read_chunk()?;
+            on_chunk(&chunk);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Advances to the next chunk of `kind`, seeking over the chunks before it without
+    /// reading their payloads or checking their CRCs. Returns `None` when IEND comes first.
+    pub fn skip_to(&mut self, kind: ChunkKind) -> Result<Option<Chunk>, Error> {
+        if self.state == State::Pending {
+            self.read_signature()?;
+        }
+
+        loop {
+            if self.state == State::Chunk(ChunkKind::IEND) {
+                return Ok(None);
+            }
+
+            let chunk = self.next_chunk(false, None)?;
+            if chunk.kind == kind {
+                return Ok(Some(chunk));
+            }
+        }
+    }
+
+    /// Structural fingerprint combining the type, length and stored CRC of every chunk.
+    ///
+    /// No pixels are decoded, payloads are only read to check CRCs: byte-identical files
+    /// share a fingerprint, files differing only in an ancillary chunk do not. It identifies
+    /// the encoding, two files with the same pixels but different compression differ too.
+    /// The decoder is rewound and left after IEND.
+    pub fn fingerprint(&mut self) -> Result<u64, Error> {
+        // 64-bit FNV-1a
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut hash = FNV_OFFSET_BASIS;
+
+        self.scan_chunks(|_, chunk| {
+            let kind: &'static [u8; 4] = chunk.kind.into();
+            let mut length = [0u8; 4];
+            NetworkEndian::write_u32(&mut length, chunk.length);
+
+            for byte in kind.iter().chain(length.iter()).chain(chunk.crc.iter()) {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+
+            Ok(false)
+        })?;
+
+        Ok(hash)
+    }
+
+    /// Reads the data of a chunk returned earlier, the read position of the decoder is kept.
+    pub fn read_chunk_data(&mut self, chunk: &Chunk) -> Result<Vec<u8>, Error> {
+        let pos = self.handle.seek(SeekFrom::Current(0))?;
+
+        let mut data: Vec<u8> = vec![0u8; chunk.length as usize];
+        self.handle.seek(SeekFrom::Start(chunk.offset))?;
+        let res = self.handle.read_exact(&mut data);
+
+        self.handle.seek(SeekFrom::Start(pos))?;
+        res?;
+
+        Ok(data)
+    }
+
+    /// Returns the image header, reading on from the current state (signature included)
+    /// until the IHDR chunk. The header is kept, later calls do not touch the handle.
+    pub fn read_header(&mut self) -> Result<Header, Error> {
+        if self.state == State::Pending {
+            self.read_signature()?;
+        }
+
+        // IHDR comes first, only an Apple CgBI chunk may precede it; next_chunk parses and keeps it
+        while self.header.is_none() && self.state != State::TrailerChunk {
+            let chunk = self.read_chunk()?;
+
+            if chunk.kind != ChunkKind::IHDR && chunk.kind != ChunkKind::CgBI {
+                break;
+            }
+        }
+
+        self.header.ok_or(Error::Format("missing IHDR chunk"))
+    }
+
+    fn parse_header(&mut self, chunk: &Chunk, data: &[u8]) -> Result<Header, Error> {
+        if chunk.kind != ChunkKind::IHDR || chunk.length != 13 {
+            return Err(Error::Format("invalid IHDR chunk"));
+        }
+
+        let width = NetworkEndian::read_u32(&data[0..4]);
+        let height = NetworkEndian::read_u32(&data[4..8]);
+
+        if width == 0 || height == 0 {
+            return Err(Error::Format("invalid image dimensions"));
+        }
+
+        let bitdepth = BitDepth::try_from(data[8]).map_err(|_| Error::Format("invalid bit depth"))?;
+        let color = Color::try_from(data[9]).map_err(|_| Error::Format("invalid color type"))?;
+
+        if !color.is_valid_bitdepth(bitdepth) {
+            return Err(Error::Format("bit depth not allowed for color type"));
+        }
+
+        // the spec defines a single compression and filter method, anything else is from a
+        // future version this decoder cannot read
+        let compression = CompressionMethod::try_from(data[10]).map_err(|_| Error::Format("invalid compression method"))?;
+        let filter = FilterMethod::try_from(data[11]).map_err(|_| Error::Format("invalid filter method"))?;
+        let interlace = Interlace::try_from(data[12]).map_err(|_| Error::Format("invalid interlace method"))?;
+
+        Ok(Header {
+            width: width,
+            height: height,
+            bitdepth: bitdepth,
+            color: color,
+            compression_method: compression,
+            filter_method: filter,
+            interlace_method: interlace,
+        })
+    }
+
+    /// Counts the chunks of the file in one pass that decodes only the IHDR payload, the
+    /// others are read for their CRC or, with CRC validation off, seeked over.
+    /// The decoder is rewound and left after IEND.
+    pub fn summary(&mut self) -> Result<Summary, Error> {
+        let mut chunk_count = 0usize;
+        let mut chunk_counts: HashMap<ChunkKind, usize> = HashMap::new();
+        let mut idat_bytes = 0u64;
+
+        self.scan_chunks(|_, chunk| {
+            chunk_count += 1;
+            *chunk_counts.entry(chunk.kind).or_insert(0) += 1;
+
+            if chunk.kind == ChunkKind::IDAT {
+                idat_bytes += chunk.length as u64;
+            }
+
+            Ok(false)
+        })?;
+
+        let header = self.header.ok_or(Error::Format("missing IHDR chunk"))?;
+
+        Ok(Summary {
+            header: header,
+            chunk_count: chunk_count,
+            idat_bytes: idat_bytes,
+            interlaced: header.interlace_method == Interlace::Adam7,
+            animated: chunk_counts.contains_key(&ChunkKind::acTL),
+            chunk_counts: chunk_counts,
+        })
+    }
+
+    /// Values of every `tEXt`, `zTXt` and `iTXt` chunk whose keyword is exactly `keyword`,
+    /// in file order. The decoder is rewound and left after IEND.
+    pub fn text_by_keyword(&mut self, keyword: &str) -> Result<Vec<String>, Error> {
+        let mut values: Vec<String> = Vec::new();
+
+        self.scan_chunks(|decoder, chunk| {
+            match chunk.kind {
+                ChunkKind::tEXt | ChunkKind::zTXt | ChunkKind::iTXt => {
+                    let text = decoder.read_text(chunk)?;
+                    if text.keyword == keyword {
+                        values.push(text.text);
+                    }
+                },
+                _ => { },
+            }
+
+            Ok(false)
+        })?;
+
+        Ok(values)
+    }
+
+    /// XMP packet from the `iTXt` chunk with the `XML:com.adobe.xmp` keyword.
+    /// The decoder is rewound and left at that chunk, or after IEND if there is none.
+    pub fn xmp(&mut self) -> Result<Option<String>, Error> {
+        let mut xmp: Option<String> = None;
+
+        self.scan_chunks(|decoder, chunk| {
+            if chunk.kind == ChunkKind::iTXt {
+                let text = decoder.read_text(chunk)?;
+                if text.keyword == "XML:com.adobe.xmp" {
+                    xmp = Some(text.text);
+                    return Ok(true);
+                }
+            }
+
+            Ok(false)
+        })?;
+
+        Ok(xmp)
+    }
+
+    /// Inflates the IDAT chunks into the filtered scanlines, each prefixed with its filter
+    /// type byte, ready for `unfilter` or `deinterlace`.
+    ///
+    /// The chunks are streamed through one inflate pass in file order, however the data is
+    /// split. Output stops at the size the header calls for, extra data is ignored and less is
+    /// an error.
+    /// `CgBI` images are inflated as raw deflate. The decoder is rewound and left after IEND.
+    pub fn read_image(&mut self) -> Result<Vec<u8>, Error> {
+        let idats = self.idat_chunks()?;
+
+        let header = self.checked_header()?;
+        let cgbi = self.cgbi;
+
+        let reader = IdatReader {
+            handle: &mut self.handle,
+            chunks: idats,
+            index: 0,
+            consumed: 0,
+        };
+
+        inflate_idat(&header, cgbi, reader)
+    }
+
+    /// The header of an image about to be decoded, within the pixel budget.
+    fn checked_header(&self) -> Result<Header, Error> {
+        let header = self.header.ok_or(Error::Format("missing IHDR chunk"))?;
+
+        if header.width as u64 * header.height as u64 > self.max_pixels {
+            return Err(Error::Format("image exceeds the pixel budget"));
+        }
+
+        Ok(header)
+    }
+
+    /// Reads on from the current chunk up to IEND and inflates the IDAT data as `read_image`
+    /// does. The handle only moves forward, so unlike `read_image` this works on a
+    /// `ForwardOnly` handle such as stdin.
+    pub fn read_image_forward(&mut self) -> Result<Vec<u8>, Error> {
+        if self.state == State::Pending {
+            self.read_signature()?;
+        }
+
+        let mut compressed: Vec<u8> = Vec::new();
+
+        while self.state != State::Chunk(ChunkKind::IEND) {
+            let (chunk, data) = self.read_chunk_with_data()?;
+            if chunk.kind == ChunkKind::IDAT {
+                compressed.extend_from_slice(&data);
+            }
+        }
+
+        let header = self.checked_header()?;
+
+        inflate_idat(&header, self.cgbi, &compressed[..])
+    }
+
+    /// Decodes the image one scanline at a time and calls `on_pixel` with the column, row and
+    /// samples of every pixel, so the whole image is never held in memory.
+    ///
+    /// There is one sample per channel, samples below 8 bits are unpacked to a byte each,
+    /// scaled only with `set_scale_grayscale_to_8bit`. `CgBI` images come out as straight RGBA.
+    /// Interlaced and 16-bit images are not supported.
+    /// The decoder is rewound and left after IEND.
+    pub fn for_each_pixel<F: FnMut(u32, u32, &[u8])>(&mut self, mut on_pixel: F) -> Result<(), Error> {
+        let idats = self.idat_chunks()?;
+
+        let header = self.checked_header()?;
+
+        if header.interlace_method != Interlace::None {
+            return Err(Error::Format("interlaced images are not supported"));
+        }
+        if header.bitdepth == BitDepth::Sixteen {
+            return Err(Error::Format("16-bit images are not supported"));
+        }
+        // Apple's iOS-optimized images are premultiplied BGRA, given as RGBA here
+        let cgbi = self.cgbi;
+        if cgbi && header.color != Color::TruecolourWithAlpha {
+            return Err(Error::Format("CgBI images other than RGBA are not supported"));
+        }
+
+        let bitdepth: u8 = header.bitdepth.into();
+        let bitdepth = bitdepth as usize;
+        let samples = header.color.samples();
+        let stride = header.scanline_size(header.width);
+        let bpp = header.bytes_per_pixel();
+        // 255 / (2^bitdepth - 1): 255 for 1-bit, 85 for 2-bit, 17 for 4-bit
+        let scale = if self.scale_grayscale_to_8bit && header.color == Color::Greyscale && bitdepth < 8 {
+            255 / ((1u8 << bitdepth) - 1)
+        } else {
+            1
+        };
+
+        let reader = IdatReader {
+            handle: &mut self.handle,
+            chunks: idats,
+            index: 0,
+            consumed: 0,
+        };
+        let mut zlib_decoder: Box<dyn Read + '_> = if cgbi {
+            Box::new(flate2::read::DeflateDecoder::new(reader))
+        } else {
+            Box::new(flate2::read::ZlibDecoder::new(reader))
+        };
+
+        let mut filtered: Vec<u8> = vec![0u8; stride + 1];
+        // the scanline before the first one is treated as all zero
+        let mut prior: Vec<u8> = vec![0u8; stride];
+        let mut current: Vec<u8> = vec![0u8; stride];
+        let mut pixel: Vec<u8> = vec![0u8; samples];
+
+        for y in 0..header.height {
+            if let Err(io_error) = zlib_decoder.read_exact(&mut filtered) {
+                if io_error.kind() == io::ErrorKind::UnexpectedEof {
+                    return Err(Error::Format("not enough image data"));
+                }
+                return Err(Error::CorruptFlateStream);
+            }
+
+            let filter = match FilterType::try_from(filtered[0]) {
+                Ok(filter) => filter,
+                Err(_) => return Err(Error::Format("invalid filter type")),
+            };
+
+            unfilter_row(filter, bpp, &prior, &filtered[1..], &mut current);
+
+            for x in 0..header.width as usize {
+                if bitdepth == 8 {
+                    pixel.copy_from_slice(&current[x * samples..(x + 1) * samples]);
+                    if cgbi {
+                        cgbi_to_rgba(&mut pixel);
+                    }
+                } else {
+                    // packed most significant bit first
+                    for (i, sample) in pixel.iter_mut().enumerate() {
+                        let bit = (x * samples + i) * bitdepth;
+                        let shift = 8 - bitdepth - bit % 8;
+                        *sample = ((current[bit / 8] >> shift) & ((1u8 << bitdepth) - 1)) * scale;
+                    }
+                }
+
+                on_pixel(x as u32, y, &pixel);
+            }
+
+            mem::swap(&mut prior, &mut current);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the image may have transparent pixels: its color type has an alpha channel
+    /// or a `tRNS` chunk is present. Only chunks up to the first IDAT are read.
+    /// The decoder is rewound and left at the chunk where the answer was found.
+    pub fn has_transparency(&mut self) -> Result<bool, Error> {
+        let mut transparent = false;
+
+        self.scan_chunks(|decoder, chunk| {
+            match chunk.kind {
+                ChunkKind::IHDR => {
+                    transparent = decoder.color_type().map(|color| color.has_alpha()) == Some(true);
+                    Ok(transparent)
+                },
+                // tRNS must precede the image data
+                ChunkKind::tRNS => {
+                    transparent = true;
+                    Ok(true)
+                },
+                ChunkKind::IDAT => Ok(true),
+                _ => Ok(false),
+            }
+        })?;
+
+        Ok(transparent)
+    }
+
+    pub fn read_stereo_layout(&mut self, chunk: &Chunk) -> Result<StereoLayout, Error> {
+        if chunk.kind != ChunkKind::sTER || chunk.length != 1 {
+            return Err(Error::Format("invalid sTER chunk"));
+        }
+
+        match self.read_chunk_data(chunk)?[0] {
+            0 => Ok(StereoLayout::CrossFuse),
+            1 => Ok(StereoLayout::DivergingFuse),
+            _ => Err(Error::Format("invalid sTER mode")),
+        }
+    }
+
+    pub fn read_gif_graphic_control(&mut self, chunk: &Chunk) -> Result<GifGraphicControl, Error> {
+        if chunk.kind != ChunkKind::gIFg || chunk.length != 4 {
+            return Err(Error::Format("invalid gIFg chunk"));
+        }
+
+        let data = self.read_chunk_data(chunk)?;
+
+        Ok(GifGraphicControl {
+            disposal_method: data[0],
+            user_input: data[1] != 0,
+            delay_time: NetworkEndian::read_u16(&data[2..4]),
+        })
+    }
+
+    /// Reads a `PLTE` chunk, which needs the header: an indexed image may not have more
+    /// entries than its bit depth can address, and greyscale images may not have a palette.
+    pub fn read_palette(&mut self, chunk: &Chunk) -> Result<Palette, Error> {
+        if chunk.kind != ChunkKind::PLTE || chunk.length == 0 || chunk.length % 3 != 0 {
+            return Err(Error::Format("invalid PLTE chunk"));
+        }
+
+        let header = self.header.ok_or(Error::Format("missing IHDR chunk"))?;
+
+        if header.color.is_grayscale() {
+            return Err(Error::Format("PLTE not allowed for greyscale images"));
+        }
+
+        // truecolor images may carry a suggested palette of up to 256 entries
+        let bitdepth: u8 = if header.color.is_indexed() { header.bitdepth.into() } else { 8 };
+        if chunk.length as usize / 3 > 1 << bitdepth {
+            return Err(Error::Format("too many PLTE entries for bit depth"));
+        }
+
+        let data = self.read_chunk_data(chunk)?;
+
+        Ok(Palette(data.chunks(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect()))
+    }
+
+    pub fn read_animation_control(&mut self, chunk: &Chunk) -> Result<AnimationControl, Error> {
+        if chunk.kind != ChunkKind::acTL || chunk.length != 8 {
+            return Err(Error::Format("invalid acTL chunk"));
+        }
+
+        let data = self.read_chunk_data(chunk)?;
+
+        let num_frames = NetworkEndian::read_u32(&data[0..4]);
+        if num_frames == 0 {
+            return Err(Error::Format("invalid acTL frame count"));
+        }
+
+        Ok(AnimationControl {
+            num_frames: num_frames,
+            num_plays: NetworkEndian::read_u32(&data[4..8]),
+        })
+    }
+
+    /// Whether the file is an APNG, i.e. an `acTL` chunk precedes the first IDAT.
+    /// The decoder is rewound and left at the chunk where the answer was found.
+    pub fn is_animated(&mut self) -> Result<bool, Error> {
+        Ok(self.find_animation_control()?.is_some())
+    }
+
+    /// Frames of an APNG from `acTL`, 1 for a still image.
+    /// The decoder is rewound and left at the chunk where the answer was found.
+    pub fn frame_count(&mut self) -> Result<u32, Error> {
+        match self.find_animation_control()? {
+            Some(animation_control) => Ok(animation_control.num_frames),
+            None => Ok(1),
+        }
+    }
+
+    fn find_animation_control(&mut self) -> Result<Option<AnimationControl>, Error> {
+        let mut animation_control: Option<AnimationControl> = None;
+
+        self.scan_chunks(|decoder, chunk| {
+            match chunk.kind {
+                ChunkKind::acTL => {
+                    animation_control = Some(decoder.read_animation_control(chunk)?);
+                    Ok(true)
+                },
+                // acTL must precede the image data
+                ChunkKind::IDAT => Ok(true),
+                _ => Ok(false),
+            }
+        })?;
+
+        Ok(animation_control)
+    }
+
+    pub fn read_text(&mut self, chunk: &Chunk) -> Result<Text, Error> {
+        let data = self.read_chunk_data(chunk)?;
+
+        let (keyword, rest) = split_at_nul(&data).ok_or(Error::Format("missing text keyword"))?;
+        if keyword.len() < 1 || keyword.len() > 79 {
+            return Err(Error::Format("invalid text keyword"));
+        }
+        let keyword = latin1_to_string(keyword);
+
+        match chunk.kind {
+            ChunkKind::tEXt => {
+                Ok(Text {
+                    keyword: keyword,
+                    language_tag: String::new(),
+                    translated_keyword: String::new(),
+                    text: latin1_to_string(rest),
+                })
+            },
+            ChunkKind::zTXt => {
+                // compression method, 0 is zlib
+                if rest.len() < 1 || rest[0] != 0 {
+                    return Err(Error::Format("invalid zTXt compression method"));
+                }
+                let text = inflate_limited(&rest[1..], self.max_text_size)?;
+
+                Ok(Text {
+                    keyword: keyword,
+                    language_tag: String::new(),
+                    translated_keyword: String::new(),
+                    text: latin1_to_string(&text),
+                })
+            },
+            ChunkKind::iTXt => {
+                if rest.len() < 2 || rest[1] != 0 {
+                    return Err(Error::Format("invalid iTXt compression method"));
+                }
+                let compressed = rest[0] != 0;
+
+                let (language_tag, rest) = split_at_nul(&rest[2..]).ok_or(Error::Format("missing iTXt language tag"))?;
+                let (translated_keyword, rest) = split_at_nul(rest).ok_or(Error::Format("missing iTXt translated keyword"))?;
+
+                let text = if compressed {
+                    inflate_limited(rest, self.max_text_size)?
+                } else {
+                    rest.to_vec()
+                };
+
+                let utf8 = |bytes: Vec<u8>| String::from_utf8(bytes).map_err(|_| Error::Format("invalid UTF-8 in iTXt"));
+
+                Ok(Text {
+                    keyword: keyword,
+                    language_tag: latin1_to_string(language_tag),
+                    translated_keyword: utf8(translated_keyword.to_vec())?,
+                    text: utf8(text)?,
+                })
+            },
+            _ => Err(Error::Format("not a text chunk")),
+        }
+    }
+
+    fn next_chunk(&mut self, verify_crc: bool, mut data: Option<&mut Vec<u8>>) -> Result<Chunk, Error> {
+        // a loop, not recursion: a file can hold any number of chunks that are passed over
+        loop {
+            if let Some(chunk) = self.read_next_chunk(verify_crc, data.as_mut().map(|data| &mut **data))? {
+                return Ok(chunk);
+            }
+        }
+    }
+
+    /// Reads the chunk at the handle, `None` if it was passed over and the caller should
+    /// read the next one. The payload is kept in `data` when given.
+    fn read_next_chunk(&mut self, verify_crc: bool, data: Option<&mut Vec<u8>>) -> Result<Option<Chunk>, Error> {
+        let length: u32 = self.handle.read_u32::<NetworkEndian>().map_err(truncated(None))?;
+
+        // https://www.w3.org/TR/PNG/#5Chunk-layout
+        if length > i32::max_value() as u32 {
+            return Err(Error::Format("invalid chunk length"));
+        }
+
+        let mut buf = [0u8; 4];
+
+        self.handle.read_exact(&mut buf).map_err(truncated(None))?;
+
+        // bit 5 of the first type byte marks an ancillary chunk
+        if self.skip_ancillary && buf[0] & 0x20 != 0 && &buf != b"tRNS" && &buf != b"bKGD" {
+            // data and CRC
+            self.handle.seek(SeekFrom::Current(length as i64 + 4))?;
+            self.chunk_index += 1;
+            return Ok(None);
+        }
+
+        let kind: ChunkKind = {
+            if let Ok(chunk_kind) = ChunkKind::try_from(&buf) {
+                chunk_kind
             } else {
-                None
+                return Err(Error::InvalidChunk);
+            }
+        };
+
+        let pos: u64 = self.handle.seek(SeekFrom::Current(0))?;
+
+        // IHDR is parsed from the bytes read here, never by seeking back to them
+        let mut payload: Vec<u8> = Vec::new();
+
+        // The CRC covers the chunk type and data, compute it while the payload
+        // streams past instead of seeking over it and reading it a second time.
+        let crc_sum = if data.is_some() || (kind == ChunkKind::IHDR && length == 13) {
+            // grows with what is actually read, not with the length field
+            (&mut self.handle).take(length as u64).read_to_end(&mut payload).map_err(truncated(Some(kind)))?;
+            if payload.len() < length as usize {
+                return Err(Error::UnexpectedEof { chunk_kind: Some(kind) });
+            }
+
+            if verify_crc { Some(chunk_crc(&buf, &payload)) } else { None }
+        } else if verify_crc {
+            Some(stream_crc(&mut self.handle, &buf, length).map_err(truncated(Some(kind)))?)
+        } else {
+            // seeking past the end succeeds, a short payload shows up when reading the CRC
+            self.handle.seek(SeekFrom::Current(length as i64))?;
+            None
+        };
+
+        self.handle.read_exact(&mut buf).map_err(truncated(Some(kind)))?;
+        let crc: [u8; 4] = buf;
+
+        let crc_val = NetworkEndian::read_u32(&crc);
+
+        match crc_sum {
+            Some(crc_sum) if crc_sum != crc_val => {
+                return Err(Error::CrcMismatch {
+                    // the data was streamed through the digest and the CRC read,
+                    // so the handle already sits at the next length field
+                    recover: 0,
+                    crc_val: crc_val,
+                    crc_sum: crc_sum,
+                    chunk_kind: kind,
+                });
+            },
+            _ => { },
+        }
+
+        let chunk = Chunk {
+            index: self.chunk_index,
+            length: length,
+            kind: kind,
+            crc: crc,
+            offset: pos,
+        };
+
+        self.chunk_index += 1;
+
+        // IHDR and PLTE may only appear once, IDAT may be split and IEND ends the stream.
+        let duplicate = match kind {
+            ChunkKind::IHDR => mem::replace(&mut self.seen_header, true),
+            ChunkKind::PLTE => mem::replace(&mut self.seen_palette, true),
+            _ => false,
+        };
+
+        if duplicate {
+            if self.strict {
+                return Err(Error::Format("duplicate critical chunk"));
+            }
+
+            // keep the first one
+            self.warnings.push(Warning::DuplicateChunk(kind));
+            return Ok(None);
+        }
+
+        if kind == ChunkKind::CgBI {
+            self.cgbi = true;
+        }
+
+        if kind == ChunkKind::IHDR {
+            self.header = Some(self.parse_header(&chunk, &payload)?);
+        }
+
+        // 1 to 256 RGB entries
+        if kind == ChunkKind::PLTE && (length == 0 || length % 3 != 0 || length > 256 * 3) {
+            return Err(Error::Format("invalid PLTE chunk"));
+        }
+
+        // the palette must be known before the indices it resolves
+        if kind == ChunkKind::IDAT && !self.seen_palette && self.color_type() == Some(Color::Indexed) {
+            return Err(Error::Format("indexed image missing PLTE"));
+        }
+
+        self.state = State::Chunk(kind);
+
+        if let Some(data) = data {
+            *data = payload;
+        }
+
+        Ok(Some(chunk))
+    }
+}
+
+
+
+/// Reads the data of consecutive IDAT chunks as one stream, seeking over the chunk
+/// framing between them.
+struct IdatReader<'a, R: 'a> {
+    handle: &'a mut R,
+    chunks: Vec<Chunk>,
+    index: usize,
+    consumed: u32,
+}
+
+impl<'a, R: Read + Seek> Read for IdatReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // a zero byte read below means truncation, don't confuse it with an empty buffer
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let chunk = match self.chunks.get(self.index) {
+                Some(chunk) => *chunk,
+                None => return Ok(0),
+            };
+
+            // also steps over zero-length IDAT chunks, which are legal and add nothing
+            if self.consumed == chunk.length {
+                self.index += 1;
+                self.consumed = 0;
+                continue;
+            }
+
+            let amt = cmp::min(buf.len(), (chunk.length - self.consumed) as usize);
+
+            self.handle.seek(SeekFrom::Start(chunk.offset + self.consumed as u64))?;
+            let amt = self.handle.read(&mut buf[..amt])?;
+            if amt == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            self.consumed += amt as u32;
+
+            return Ok(amt);
+        }
+    }
+}
+
+/// Lets a `Decoder` read from a handle that can't seek, such as stdin or a pipe.
+///
+/// The position is counted and seeking forward reads and drops the bytes in between,
+/// seeking back fails. Only the forward path works on it: `read_signature`, `read_header`,
+/// `read_chunk`, `read_chunk_with_data`, `skip_to` and `read_image_forward`.
+pub struct ForwardOnly<R: Read> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> ForwardOnly<R> {
+    pub fn new(inner: R) -> Self {
+        ForwardOnly {
+            inner: inner,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ForwardOnly<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amt = self.inner.read(buf)?;
+        self.position += amt as u64;
+        Ok(amt)
+    }
+}
+
+impl<R: Read> Seek for ForwardOnly<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) if delta >= 0 => self.position.checked_add(delta as u64),
+            _ => None,
+        };
+
+        match target {
+            Some(target) if target >= self.position => {
+                io::copy(&mut (&mut self.inner).take(target - self.position), &mut io::sink())?;
+                // like a file, seeking past the end succeeds and the next read finds nothing
+                self.position = target;
+                Ok(target)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Other, "cannot seek back on a forward-only handle")),
+        }
+    }
+}
+
+impl<Handle: Read + Seek> Iterator for Decoder<Handle> {
+    type Item = Element;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.state == State::Pending {
+            if let Ok(signature) = self.read_signature() {
+                Some(Element::Signature(signature))
+            } else {
+                None
+            }
+        } else if self.state == State::Chunk(ChunkKind::IEND) {
+            None
+        } else {
+            if let Ok(chunk) = self.read_chunk() {
+                Some(Element::Chunk(chunk))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// The chunk count is unknown until IEND, after which the iterator is exhausted.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.state == State::Chunk(ChunkKind::IEND) {
+            (0, Some(0))
+        } else {
+            (0, None)
+        }
+    }
+}
+
+
+// https://www.w3.org/TR/PNG/#8Interlace
+/// Column start, row start, column step and row step of the seven Adam7 passes.
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Width and height of the reduced image of each Adam7 pass.
+///
+/// For images narrower or shorter than 8 pixels some passes are empty (zero wide or
+/// zero high). An empty pass has no scanlines and no filter bytes in the IDAT stream
+/// and must be skipped, not treated as one row of zero width.
+pub fn adam7_pass_dimensions(width: u32, height: u32) -> [(u32, u32); 7] {
+    let mut dimensions = [(0u32, 0u32); 7];
+
+    for (pass, &(x0, y0, dx, dy)) in ADAM7_PASSES.iter().enumerate() {
+        let pass_width = if width > x0 { (width - x0 + dx - 1) / dx } else { 0 };
+        let pass_height = if height > y0 { (height - y0 + dy - 1) / dy } else { 0 };
+
+        if pass_width == 0 || pass_height == 0 {
+            dimensions[pass] = (0, 0);
+        } else {
+            dimensions[pass] = (pass_width, pass_height);
+        }
+    }
+
+    debug_assert_eq!(dimensions.iter().map(|&(w, h)| w as u64 * h as u64).sum::<u64>(),
+                     width as u64 * height as u64);
+
+    dimensions
+}
+
+/// Reverses the scanline filters of a non-interlaced image.
+///
+/// `data` is the inflated IDAT stream, every scanline prefixed with its filter type byte.
+/// The result holds the reconstructed scanlines back to back, without filter bytes:
+///
+/// * rows run top to bottom, pixels left to right, samples in `Color` order
+/// * each row is `header.scanline_size(header.width)` bytes, with no padding between rows
+/// * samples below 8 bits are packed most significant bit first, a row starts on a byte boundary
+/// * 16-bit samples are big-endian
+pub fn unfilter(header: &Header, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let stride = header.scanline_size(header.width);
+    unfilter_scanlines(header.bytes_per_pixel(), stride, header.height as usize, data)
+}
+
+/// Reverses the scanline filters of an Adam7 interlaced image and reassembles the passes.
+///
+/// `data` is the inflated IDAT stream holding the non-empty passes one after another.
+/// Each pass is unfiltered on its own: the first scanline of a pass looks back at a row of
+/// zeros, never at the last scanline of the previous pass. The result has the same layout
+/// as the output of `unfilter`.
+pub fn deinterlace(header: &Header, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if header.interlace_method != Interlace::Adam7 {
+        return Err(Error::Format("not an Adam7 interlaced image"));
+    }
+
+    let bitdepth: u8 = header.bitdepth.into();
+    let bits_per_pixel = header.color.samples() * bitdepth as usize;
+    let bpp = header.bytes_per_pixel();
+    let stride = header.scanline_size(header.width);
+
+    let size = stride.checked_mul(header.height as usize).ok_or(Error::Format("image too large"))?;
+    let mut pixels: Vec<u8> = vec![0u8; size];
+
+    let mut offset = 0usize;
+
+    let dimensions = adam7_pass_dimensions(header.width, header.height);
+
+    for (pass, &(x0, y0, dx, dy)) in ADAM7_PASSES.iter().enumerate() {
+        let (pass_width, pass_height) = dimensions[pass];
+        if pass_width == 0 {
+            continue;
+        }
+
+        let pass_stride = header.scanline_size(pass_width);
+        let pass_pixels = unfilter_scanlines(bpp, pass_stride, pass_height as usize, &data[offset..])?;
+        offset += (pass_stride + 1) * pass_height as usize;
+
+        for py in 0..pass_height as usize {
+            let y = y0 as usize + py * dy as usize;
+            let src_row = &pass_pixels[py * pass_stride..(py + 1) * pass_stride];
+            let dst_row = &mut pixels[y * stride..(y + 1) * stride];
+
+            for px in 0..pass_width as usize {
+                let x = x0 as usize + px * dx as usize;
+
+                if bits_per_pixel >= 8 {
+                    let n = bits_per_pixel / 8;
+                    dst_row[x * n..(x + 1) * n].copy_from_slice(&src_row[px * n..(px + 1) * n]);
+                } else {
+                    // sub-byte pixels are packed most significant bit first
+                    let mask = (1u8 << bits_per_pixel) - 1;
+                    let src_bit = px * bits_per_pixel;
+                    let dst_bit = x * bits_per_pixel;
+                    let value = (src_row[src_bit / 8] >> (8 - bits_per_pixel - src_bit % 8)) & mask;
+                    dst_row[dst_bit / 8] |= value << (8 - bits_per_pixel - dst_bit % 8);
+                }
+            }
+        }
+    }
+
+    Ok(pixels)
+}
+
+fn unfilter_scanlines(bpp: usize, stride: usize, height: usize, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let size = stride.checked_mul(height).ok_or(Error::Format("image too large"))?;
+
+    if data.len() < size + height {
+        return Err(Error::Format("not enough image data"));
+    }
+
+    let data = &data[..size + height];
+
+    // Fast path: with filter type None everywhere (common for screenshots) reconstruction
+    // is just dropping the filter bytes, no need to zero the buffer or look at prior rows.
+    if data.chunks(stride + 1).all(|filtered| filtered[0] == 0) {
+        let mut pixels: Vec<u8> = Vec::with_capacity(size);
+        for filtered in data.chunks(stride + 1) {
+            pixels.extend_from_slice(&filtered[1..]);
+        }
+        return Ok(pixels);
+    }
+
+    unfilter_each_row(bpp, stride, data)
+}
+
+/// The general path of `unfilter_scanlines`, for `data` of whole filtered scanlines.
+fn unfilter_each_row(bpp: usize, stride: usize, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut pixels: Vec<u8> = vec![0u8; data.len() / (stride + 1) * stride];
+    // the scanline before the first one is treated as all zero
+    let zeros: Vec<u8> = vec![0u8; stride];
+
+    for (y, filtered) in data.chunks(stride + 1).enumerate() {
+        let filter = match FilterType::try_from(filtered[0]) {
+            Ok(filter) => filter,
+            Err(_) => return Err(Error::Format("invalid filter type")),
+        };
+        let filtered = &filtered[1..];
+
+        let (done, rest) = pixels.split_at_mut(y * stride);
+        let prior: &[u8] = if y == 0 { &zeros } else { &done[(y - 1) * stride..] };
+        let current = &mut rest[..stride];
+
+        unfilter_row(filter, bpp, prior, filtered, current);
+    }
+
+    Ok(pixels)
+}
+
+/// Reconstructs one scanline into `current` from its `filtered` bytes and the reconstructed
+/// `prior` scanline, all three `stride` bytes long.
+fn unfilter_row(filter: FilterType, bpp: usize, prior: &[u8], filtered: &[u8], current: &mut [u8]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") && simd::unfilter_row(filter, bpp, prior, filtered, current) {
+            return;
+        }
+    }
+
+    unfilter_row_scalar(filter, bpp, prior, filtered, current);
+}
+
+fn unfilter_row_scalar(filter: FilterType, bpp: usize, prior: &[u8], filtered: &[u8], current: &mut [u8]) {
+    let stride = current.len();
+
+    match filter {
+        FilterType::None => {
+            current.copy_from_slice(filtered);
+        },
+        FilterType::Sub => {
+            for i in 0..stride {
+                let left = if i >= bpp { current[i - bpp] } else { 0 };
+                current[i] = filtered[i].wrapping_add(left);
+            }
+        },
+        FilterType::Up => {
+            for i in 0..stride {
+                current[i] = filtered[i].wrapping_add(prior[i]);
+            }
+        },
+        FilterType::Average => {
+            for i in 0..stride {
+                let left = if i >= bpp { current[i - bpp] } else { 0 };
+                let avg = (left as u16 + prior[i] as u16) / 2;
+                current[i] = filtered[i].wrapping_add(avg as u8);
+            }
+        },
+        FilterType::Paeth => {
+            for i in 0..stride {
+                let (left, upper_left) = if i >= bpp { (current[i - bpp], prior[i - bpp]) } else { (0, 0) };
+                current[i] = filtered[i].wrapping_add(paeth_predictor(left, prior[i], upper_left));
+            }
+        },
+    }
+}
+
+/// SSE2 versions of the inverse filters, after libpng's `filter_sse2_intrinsics.c`.
+///
+/// Up adds 16 bytes at a time. Sub, Average and Paeth depend on the reconstructed pixel to
+/// the left, so they run one pixel at a time with the `bpp` samples in parallel lanes,
+/// for 3 and 4 bytes per pixel only.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use super::FilterType;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::arch::x86_64::*;
+
+    /// Returns `false` when the filter and pixel size have no SIMD version, `current` is
+    /// then left untouched for the scalar code.
+    pub fn unfilter_row(filter: FilterType, bpp: usize, prior: &[u8], filtered: &[u8], current: &mut [u8]) -> bool {
+        match filter {
+            FilterType::None => false,
+            FilterType::Up => {
+                unsafe { up(prior, filtered, current) };
+                true
+            },
+            _ if bpp == 3 || bpp == 4 => {
+                unsafe { by_pixel(filter, bpp, prior, filtered, current) };
+                true
+            },
+            _ => false,
+        }
+    }
+
+    unsafe fn up(prior: &[u8], filtered: &[u8], current: &mut [u8]) {
+        let stride = current.len();
+        let mut i = 0;
+
+        while i + 16 <= stride {
+            let b = _mm_loadu_si128(prior[i..].as_ptr() as *const __m128i);
+            let x = _mm_loadu_si128(filtered[i..].as_ptr() as *const __m128i);
+            _mm_storeu_si128(current[i..].as_mut_ptr() as *mut __m128i, _mm_add_epi8(x, b));
+            i += 16;
+        }
+
+        for i in i..stride {
+            current[i] = filtered[i].wrapping_add(prior[i]);
+        }
+    }
+
+    unsafe fn load(bytes: &[u8], bpp: usize) -> __m128i {
+        let mut pixel = [0u8; 4];
+        pixel[..bpp].copy_from_slice(&bytes[..bpp]);
+        _mm_cvtsi32_si128(LittleEndian::read_i32(&pixel))
+    }
+
+    unsafe fn store(bytes: &mut [u8], bpp: usize, value: __m128i) {
+        let mut pixel = [0u8; 4];
+        LittleEndian::write_i32(&mut pixel, _mm_cvtsi128_si32(value));
+        bytes[..bpp].copy_from_slice(&pixel[..bpp]);
+    }
+
+    unsafe fn if_then_else(mask: __m128i, then: __m128i, otherwise: __m128i) -> __m128i {
+        _mm_or_si128(_mm_and_si128(mask, then), _mm_andnot_si128(mask, otherwise))
+    }
+
+    unsafe fn abs_epi16(x: __m128i) -> __m128i {
+        _mm_max_epi16(x, _mm_sub_epi16(_mm_setzero_si128(), x))
+    }
+
+    unsafe fn by_pixel(filter: FilterType, bpp: usize, prior: &[u8], filtered: &[u8], current: &mut [u8]) {
+        let zero = _mm_setzero_si128();
+        let ones = _mm_set1_epi8(1);
+
+        // left and upper left pixels, zero before the first pixel
+        let mut a = zero;
+        let mut c = zero;
+
+        let mut i = 0;
+        while i + bpp <= current.len() {
+            let b = load(&prior[i..], bpp);
+            let x = load(&filtered[i..], bpp);
+
+            let predictor = match filter {
+                FilterType::Sub => a,
+                FilterType::Average => {
+                    // _mm_avg_epu8 rounds up, the filter rounds down
+                    let odd = _mm_and_si128(_mm_xor_si128(a, b), ones);
+                    _mm_sub_epi8(_mm_avg_epu8(a, b), odd)
+                },
+                FilterType::Paeth => {
+                    let a16 = _mm_unpacklo_epi8(a, zero);
+                    let b16 = _mm_unpacklo_epi8(b, zero);
+                    let c16 = _mm_unpacklo_epi8(c, zero);
+
+                    // |p - a| = |b - c|, |p - b| = |a - c|, |p - c| = |b - c + a - c|
+                    let pa = _mm_sub_epi16(b16, c16);
+                    let pb = _mm_sub_epi16(a16, c16);
+                    let pc = abs_epi16(_mm_add_epi16(pa, pb));
+                    let pa = abs_epi16(pa);
+                    let pb = abs_epi16(pb);
+
+                    // ties favor a over b over c
+                    let smallest = _mm_min_epi16(pc, _mm_min_epi16(pa, pb));
+                    let nearest = if_then_else(_mm_cmpeq_epi16(smallest, pa), a16,
+                                               if_then_else(_mm_cmpeq_epi16(smallest, pb), b16, c16));
+
+                    _mm_packus_epi16(nearest, nearest)
+                },
+                _ => unreachable!(),
+            };
+
+            a = _mm_add_epi8(x, predictor);
+            c = b;
+
+            store(&mut current[i..], bpp, a);
+            i += bpp;
+        }
+    }
+}
+
+// https://www.w3.org/TR/PNG/#9Filter-type-4-Paeth
+#[inline]
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Splits the pixels of a stereo pair, as `unfilter` returns them, into the left-eye and
+/// right-eye images, in that order, each `(header.width - padding) / 2` pixels wide.
+///
+/// The left subimage is padded with 0 to 7 pixels so the right one starts on a byte
+/// boundary whatever the bit depth: `padding = 7 - ((subimage_width - 1) % 8)`.
+pub fn split_stereo(header: &Header, layout: StereoLayout, pixels: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let width = header.width;
+
+    let subimage_width = (0..8u32).filter(|&padding| padding < width && (width - padding) % 2 == 0)
+                                  .map(|padding| ((width - padding) / 2, padding))
+                                  .find(|&(subimage_width, padding)| subimage_width > 0 && padding == 7 - (subimage_width - 1) % 8)
+                                  .map(|(subimage_width, _)| subimage_width)
+                                  .ok_or(Error::Format("width does not hold a stereo pair"))?;
+
+    let bitdepth: u8 = header.bitdepth.into();
+    let bits_per_pixel = header.color.samples() * bitdepth as usize;
+    let stride = header.scanline_size(width);
+    let sub_stride = header.scanline_size(subimage_width);
+    // the left subimage and its padding fill whole bytes
+    let right_start = (subimage_width as usize + 7) / 8 * 8 * bits_per_pixel / 8;
+    // padding pixels sharing the last byte of a left row are dropped
+    let tail_bits = (subimage_width as usize * bits_per_pixel) % 8;
+    let tail_mask = if tail_bits == 0 { 0xff } else { 0xffu8 << (8 - tail_bits) };
+
+    if pixels.len() != stride * header.height as usize {
+        return Err(Error::Format("pixel data does not match the header"));
+    }
+
+    let mut left: Vec<u8> = Vec::with_capacity(sub_stride * header.height as usize);
+    let mut right: Vec<u8> = Vec::with_capacity(sub_stride * header.height as usize);
+
+    for row in pixels.chunks(stride) {
+        left.extend_from_slice(&row[..sub_stride]);
+        *left.last_mut().unwrap() &= tail_mask;
+        right.extend_from_slice(&row[right_start..right_start + sub_stride]);
+    }
+
+    match layout {
+        StereoLayout::CrossFuse => Ok((right, left)),
+        StereoLayout::DivergingFuse => Ok((left, right)),
+    }
+}
+
+/// Converts the premultiplied BGRA pixels of a `CgBI` image into straight RGBA in place.
+pub fn cgbi_to_rgba(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_mut(4) {
+        if pixel.len() < 4 {
+            break;
+        }
+
+        pixel.swap(0, 2);
+
+        let alpha = pixel[3] as u32;
+        if alpha > 0 && alpha < 255 {
+            for sample in pixel[..3].iter_mut() {
+                let val = (*sample as u32 * 255 + alpha / 2) / alpha;
+                *sample = cmp::min(val, 255) as u8;
+            }
+        }
+    }
+}
+
+/// Expands the indices of an unfiltered indexed image into 8-bit RGBA, four bytes per pixel.
+///
+/// `indices` is the output of `unfilter` or `deinterlace`, 1, 2 and 4-bit indices are packed
+/// most significant bit first. Alpha comes from `trns`, the data of the `tRNS` chunk,
+/// entries past its end are opaque. An index past the end of the palette is an error.
+pub fn expand_indexed(header: &Header, indices: &[u8], palette: &Palette, trns: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    if header.color != Color::Indexed {
+        return Err(Error::Format("not an indexed image"));
+    }
+
+    let bitdepth: u8 = header.bitdepth.into();
+    let bitdepth = bitdepth as usize;
+    let stride = header.scanline_size(header.width);
+
+    let size = stride.checked_mul(header.height as usize).ok_or(Error::Format("image too large"))?;
+    if indices.len() < size {
+        return Err(Error::Format("not enough image data"));
+    }
+
+    let trns = trns.unwrap_or(&[]);
+    let mask = ((1u16 << bitdepth) - 1) as u8;
+
+    let mut pixels: Vec<u8> = Vec::with_capacity(header.rgba8_size()?);
+
+    for row in indices[..size].chunks(stride) {
+        for x in 0..header.width as usize {
+            let bit = x * bitdepth;
+            let index = (row[bit / 8] >> (8 - bitdepth - bit % 8)) & mask;
+
+            let rgb = palette.get(index).ok_or(Error::Format("palette index out of range"))?;
+            let alpha = trns.get(index as usize).cloned().unwrap_or(255);
+
+            pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], alpha]);
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Assembles the big-endian samples of an unfiltered 16-bit RGBA image, four per pixel.
+///
+/// `pixels` is the output of `unfilter`, each pixel is eight bytes (R, G, B, A).
+pub fn rgba16_samples(header: &Header, pixels: &[u8]) -> Result<Vec<u16>, Error> {
+    if header.color != Color::TruecolourWithAlpha || header.bitdepth != BitDepth::Sixteen {
+        return Err(Error::Format("not a 16-bit RGBA image"));
+    }
+
+    debug_assert_eq!(header.bytes_per_pixel(), 8);
+
+    let size = header.scanline_size(header.width).checked_mul(header.height as usize)
+                                                 .ok_or(Error::Format("image too large"))?;
+    if pixels.len() < size {
+        return Err(Error::Format("not enough image data"));
+    }
+
+    let samples = pixels[..size].chunks(2)
+                                .map(|sample| NetworkEndian::read_u16(sample))
+                                .collect::<Vec<u16>>();
+
+    Ok(samples)
+}
+
+/// Scales 16-bit samples down to 8 bits by keeping the high byte.
+pub fn samples16_to_8(samples: &[u16]) -> Vec<u8> {
+    samples.iter().map(|&sample| (sample >> 8) as u8).collect()
+}
+
+/// Maps a short read inside a chunk to `Error::UnexpectedEof`, other I/O errors pass through.
+fn truncated(chunk_kind: Option<ChunkKind>) -> impl Fn(io::Error) -> Error {
+    move |io_error| {
+        if io_error.kind() == io::ErrorKind::UnexpectedEof {
+            Error::UnexpectedEof { chunk_kind: chunk_kind }
+        } else {
+            Error::IoError(io_error)
+        }
+    }
+}
+
+/// Splits off the bytes before the first NUL, the NUL itself is dropped.
+fn split_at_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    data.iter().position(|&byte| byte == 0).map(|pos| (&data[..pos], &data[pos + 1..]))
+}
+
+fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+/// Inflates the IDAT stream of an image, raw deflate for `CgBI`, up to the size the header
+/// gives for the filtered scanlines. A stream that ends short of that size is an error.
+fn inflate_idat<R: Read>(header: &Header, cgbi: bool, reader: R) -> Result<Vec<u8>, Error> {
+    let size = header.filtered_size()?;
+
+    // no capacity up front: `size` comes from the header, the buffer only grows
+    // with what actually inflates
+    let mut data: Vec<u8> = Vec::new();
+    let res = if cgbi {
+        flate2::read::DeflateDecoder::new(reader).take(size as u64).read_to_end(&mut data)
+    } else {
+        flate2::read::ZlibDecoder::new(reader).take(size as u64).read_to_end(&mut data)
+    };
+
+    if res.is_err() {
+        return Err(Error::CorruptFlateStream);
+    }
+
+    if data.len() < size {
+        return Err(Error::Format("image data ends before the last scanline"));
+    }
+
+    Ok(data)
+}
+
+/// CRC of a chunk, over its type and data.
+fn chunk_crc(kind: &[u8; 4], data: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(kind);
+    digest.write(data);
+    digest.sum32()
+}
+
+/// Inflates a zlib stream, failing once the output grows beyond `limit` bytes.
+fn inflate_limited(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut output: Vec<u8> = Vec::new();
+
+    flate2::read::ZlibDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|_| Error::CorruptFlateStream)?;
+
+    if output.len() > limit {
+        return Err(Error::Format("text chunk too large"));
+    }
+
+    Ok(output)
+}
+
+/// CRC-32 over the chunk type and the next `length` bytes of `handle`.
+fn stream_crc<R: Read>(handle: &mut R, kind: &[u8; 4], length: u32) -> io::Result<u32> {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(kind);
+
+    let mut buffer = [0u8; CHUNK_BUFFER_SIZE];
+    let mut remaining = length as u64;
+
+    while remaining > 0 {
+        let amt = cmp::min(remaining, CHUNK_BUFFER_SIZE as u64) as usize;
+        handle.read_exact(&mut buffer[..amt])?;
+        digest.write(&buffer[..amt]);
+        remaining -= amt as u64;
+    }
+
+    Ok(digest.sum32())
+}
+
+/// Verifies the CRC of every chunk of the file at `path` on `num_cpus` threads.
+///
+/// A first pass collects the chunk table by seeking over the payloads, then every
+/// worker opens its own handle and checks an interleaved share of the chunks.
+/// If several chunks are damaged the error always reports the earliest one.
+pub fn validate_crcs_parallel<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let path = path.as_ref().to_path_buf();
+
+    let mut decoder = Decoder::new(File::open(&path)?);
+    let mut chunks: Vec<Chunk> = Vec::new();
+
+    // the workers check the CRCs
+    decoder.set_crc_validation(false);
+    decoder.scan_chunks(|_, chunk| {
+        chunks.push(*chunk);
+        Ok(false)
+    })?;
+
+    let chunks = Arc::new(chunks);
+    let threads = cmp::max(1, cmp::min(num_cpus::get(), chunks.len()));
+
+    let workers = (0..threads).map(|n| {
+        let path = path.clone();
+        let chunks = chunks.clone();
+
+        thread::spawn(move || -> Option<(usize, Error)> {
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => return Some((n, e.into())),
+            };
+
+            // chunks are visited in file order, so the first failure is this worker's earliest
+            for (idx, chunk) in chunks.iter().enumerate().skip(n).step_by(threads) {
+                let kind: &'static [u8; 4] = chunk.kind.into();
+                let crc_val = NetworkEndian::read_u32(&chunk.crc);
+
+                let crc_sum = file.seek(SeekFrom::Start(chunk.offset))
+                                  .and_then(|_| stream_crc(&mut file, kind, chunk.length));
+
+                match crc_sum {
+                    Ok(crc_sum) if crc_sum == crc_val => { },
+                    Ok(crc_sum) => {
+                        return Some((idx, Error::CrcMismatch {
+                            recover: 0,
+                            crc_val: crc_val,
+                            crc_sum: crc_sum,
+                            chunk_kind: chunk.kind,
+                        }));
+                    },
+                    Err(e) => return Some((idx, e.into())),
+                }
+            }
+
+            None
+        })
+    }).collect::<Vec<_>>();
+
+    let mut first: Option<(usize, Error)> = None;
+
+    for worker in workers {
+        match worker.join() {
+            Ok(Some((idx, e))) => {
+                if first.as_ref().map(|&(first_idx, _)| idx < first_idx).unwrap_or(true) {
+                    first = Some((idx, e));
+                }
+            },
+            Ok(None) => { },
+            Err(_) => return Err(Error::Other("CRC worker thread panicked")),
+        }
+    }
+
+    match first {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Writes a single chunk: the big-endian data length, the chunk type, the data
+/// and the CRC-32 over type and data. Every encoded chunk goes through here.
+pub fn write_chunk<W: Write>(w: &mut W, kind: ChunkKind, data: &[u8]) -> io::Result<()> {
+    // https://www.w3.org/TR/PNG/#5Chunk-layout
+    if data.len() > i32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk data exceeds 2^31-1 bytes"));
+    }
+
+    let kind: &'static [u8; 4] = kind.into();
+
+    w.write_u32::<NetworkEndian>(data.len() as u32)?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_u32::<NetworkEndian>(chunk_crc(kind, data))?;
+
+    Ok(())
+}
+
+/// Copies a PNG with every ancillary chunk removed, except `tRNS` and `bKGD` which change
+/// how the pixels are shown. The IDAT data is copied as is, without recompressing.
+///
+/// APNG frame chunks are ancillary as well, an animation comes out as its default image.
+pub fn strip_metadata<R: Read + Seek, W: Write>(input: R, mut output: W) -> Result<(), Error> {
+    let mut decoder = Decoder::new(input);
+    decoder.set_skip_ancillary(true);
+    decoder.read_signature()?;
+
+    output.write_all(&SIGNATURE)?;
+
+    loop {
+        let (chunk, data) = decoder.read_chunk_with_data()?;
+        let kind: &'static [u8; 4] = chunk.kind.into();
+
+        // the CRC was checked on these bytes, copy the chunk as it is
+        output.write_u32::<NetworkEndian>(chunk.length)?;
+        output.write_all(kind)?;
+        output.write_all(&data)?;
+        output.write_all(&chunk.crc)?;
+
+        if chunk.kind == ChunkKind::IEND {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+
+fn main(){
+    let core = num_cpus::get_physical();
+    let threads = num_cpus::get();
+
+    println!("Core: {:?} Threads: {:?}", core, threads);
+
+    // let filepath = "/Users/luozijun/Pictures/qmshtu.png";
+    let filepath = "output.png";
+
+    let mut decoder = Decoder::new(File::open(filepath).unwrap());
+
+    let now = Instant::now();
+
+    let data = decoder.read_image().unwrap();
+    let header = decoder.header().unwrap();
+
+    let pixels = if header.interlace_method == Interlace::Adam7 {
+        deinterlace(&header, &data).unwrap()
+    } else {
+        unfilter(&header, &data).unwrap()
+    };
+
+    println!("{:?}", header);
+    println!("Pixels: {:?} Bytes  elapsed: {:?} seconds", pixels.len(), (now.elapsed().as_millis() as f64) / 1000.0);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::{ env, fs, process };
+
+    fn header_data(width: u32, height: u32, bitdepth: u8, color: u8, interlace: u8) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        data.write_u32::<NetworkEndian>(width).unwrap();
+        data.write_u32::<NetworkEndian>(height).unwrap();
+        data.extend_from_slice(&[bitdepth, color, 0, 0, interlace]);
+        data
+    }
+
+    fn header(width: u32, height: u32, bitdepth: u8, color: u8, interlace: u8) -> Header {
+        Header {
+            width: width,
+            height: height,
+            bitdepth: BitDepth::try_from(bitdepth).unwrap(),
+            color: Color::try_from(color).unwrap(),
+            compression_method: CompressionMethod::Deflate,
+            filter_method: FilterMethod::Adaptive,
+            interlace_method: Interlace::try_from(interlace).unwrap(),
+        }
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn png(chunks: &[(ChunkKind, &[u8])]) -> Vec<u8> {
+        let mut file: Vec<u8> = SIGNATURE.to_vec();
+        for &(kind, data) in chunks.iter() {
+            write_chunk(&mut file, kind, data).unwrap();
+        }
+        file
+    }
+
+    /// 2x2 8-bit RGB, every scanline with filter type None.
+    fn rgb_2x2() -> (Vec<u8>, Vec<u8>) {
+        let pixels: Vec<u8> = (0..12).collect();
+        let filtered: Vec<u8> = pixels.chunks(6).flat_map(|row| {
+            let mut scanline = vec![0u8];
+            scanline.extend_from_slice(row);
+            scanline
+        }).collect();
+
+        (filtered, pixels)
+    }
+
+    /// `count` copies of the same chunk, built once: write_chunk is slow in debug builds.
+    fn repeated_chunk(kind: ChunkKind, data: &[u8], count: usize) -> Vec<u8> {
+        let mut chunk: Vec<u8> = Vec::new();
+        write_chunk(&mut chunk, kind, data).unwrap();
+
+        let mut chunks: Vec<u8> = Vec::with_capacity(chunk.len() * count);
+        for _ in 0..count {
+            chunks.extend_from_slice(&chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn many_duplicate_headers_do_not_overflow_the_stack() {
+        let header = header_data(2, 2, 8, 2, 0);
+        let (filtered, _) = rgb_2x2();
+
+        let mut file = png(&[(ChunkKind::IHDR, &header)]);
+        file.extend(repeated_chunk(ChunkKind::IHDR, &header, 100_000));
+        write_chunk(&mut file, ChunkKind::IDAT, &zlib(&filtered)).unwrap();
+        write_chunk(&mut file, ChunkKind::IEND, &[]).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_strict(false);
+        decoder.set_crc_validation(false);
+
+        assert_eq!(decoder.read_chunks_with(|_| { }).unwrap(), 3);
+        assert_eq!(decoder.warnings().len(), 100_000);
+    }
+
+    #[test]
+    fn many_skipped_chunks_do_not_overflow_the_stack() {
+        let header = header_data(2, 2, 8, 2, 0);
+        let (filtered, _) = rgb_2x2();
+
+        let mut file = png(&[(ChunkKind::IHDR, &header)]);
+        file.extend(repeated_chunk(ChunkKind::tEXt, &[], 100_000));
+        write_chunk(&mut file, ChunkKind::IDAT, &zlib(&filtered)).unwrap();
+        write_chunk(&mut file, ChunkKind::IEND, &[]).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_skip_ancillary(true);
+
+        assert_eq!(decoder.read_chunks_with(|_| { }).unwrap(), 3);
+    }
+
+    fn rgb_2x2_png() -> Vec<u8> {
+        let (filtered, _) = rgb_2x2();
+        png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Comment\0hello"),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ])
+    }
+
+    #[test]
+    fn read_image_inflates_the_scanlines() {
+        let (filtered, pixels) = rgb_2x2();
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+
+        let data = decoder.read_image().unwrap();
+        assert_eq!(data, filtered);
+        assert_eq!(unfilter(&decoder.header().unwrap(), &data).unwrap(), pixels);
+
+        // a second call scans the file again
+        assert_eq!(decoder.read_image().unwrap(), filtered);
+    }
+
+    #[test]
+    fn read_image_reports_a_corrupt_stream() {
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, &[0x78, 0x9c, 0xff, 0xff, 0xff, 0xff]),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        match Decoder::new(Cursor::new(file)).read_image() {
+            Err(Error::CorruptFlateStream) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_image_does_not_trust_the_header_size() {
+        let (filtered, _) = rgb_2x2();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(1_000_000, 200_000, 8, 6, 0)),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_max_pixels(u64::max_value());
+        match decoder.read_image() {
+            Err(Error::Format("image data ends before the last scanline")) => { },
+            other => panic!("{:?}", other.map(|data| data.len())),
+        }
+    }
+
+    #[test]
+    fn truncated_image_data_is_an_error() {
+        let (filtered, _) = rgb_2x2();
+
+        // a complete zlib stream holding only the first scanline
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, &zlib(&filtered[..7])),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        match Decoder::new(Cursor::new(file.clone())).read_image() {
+            Err(Error::Format("image data ends before the last scanline")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        let mut decoder = Decoder::new(ForwardOnly::new(Cursor::new(file)));
+        decoder.read_signature().unwrap();
+        match decoder.read_image_forward() {
+            Err(Error::Format("image data ends before the last scanline")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        // the stream itself cut off
+        let stream = zlib(&filtered);
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, &stream[..stream.len() / 2]),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        assert!(Decoder::new(Cursor::new(file)).read_image().is_err());
+    }
+
+    #[test]
+    fn embedded_png_is_scanned_from_its_own_start() {
+        let (filtered, _) = rgb_2x2();
+
+        let mut file = b"container header".to_vec();
+        let offset = file.len() as u64;
+        file.extend(rgb_2x2_png());
+
+        let mut handle = Cursor::new(file);
+        handle.seek(SeekFrom::Start(offset)).unwrap();
+
+        let mut decoder = Decoder::new(handle);
+        assert_eq!(decoder.summary().unwrap().chunk_count, 4);
+        assert_eq!(decoder.text_by_keyword("Comment").unwrap(), vec!["hello".to_string()]);
+        assert_eq!(decoder.read_image().unwrap(), filtered);
+    }
+
+    #[test]
+    fn scans_follow_the_crc_setting() {
+        let mut file = rgb_2x2_png();
+        // last byte of the tEXt payload
+        let pos = file.len() - 12 - 12 - zlib(&rgb_2x2().0).len() - 4 - 1;
+        assert_eq!(file[pos], b'o');
+        file[pos] = b'O';
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        match decoder.read_image() {
+            Err(Error::CrcMismatch { chunk_kind: ChunkKind::tEXt, .. }) => { },
+            other => panic!("{:?}", other),
+        }
+
+        decoder.set_crc_validation(false);
+        assert_eq!(decoder.text_by_keyword("Comment").unwrap(), vec!["hellO".to_string()]);
+    }
+
+    #[test]
+    fn corrupted_payload_is_a_crc_mismatch() {
+        let mut file = rgb_2x2_png();
+        // the tEXt chunk follows the signature and the 25 bytes of IHDR
+        let text = 8 + 25;
+        let length = NetworkEndian::read_u32(&file[text..text + 4]) as usize;
+        let stored = NetworkEndian::read_u32(&file[text + 8 + length..text + 12 + length]);
+        file[text + 8] ^= 0xff;
+
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&file[text + 4..text + 8 + length]);
+        let computed = digest.sum32();
+        assert!(computed != stored);
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        decoder.read_signature().unwrap();
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IHDR);
+
+        match decoder.read_chunk() {
+            Err(Error::CrcMismatch { recover, crc_val, crc_sum, chunk_kind }) => {
+                assert_eq!(recover, 0);
+                assert_eq!(crc_val, stored);
+                assert_eq!(crc_sum, computed);
+                assert_eq!(chunk_kind, ChunkKind::tEXt);
+            },
+            other => panic!("{:?}", other),
+        }
+
+        // skipping `recover` bytes lands on the next chunk
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IDAT);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_crc_validation(false);
+        assert_eq!(decoder.read_chunks_with(|_| { }).unwrap(), 4);
+    }
+
+    /// Filters scanlines of `bpp`-byte pixels, cycling through `filters`: the inverse of
+    /// `unfilter_scanlines`, the first row looks back at zeros.
+    fn filter_rows(bpp: usize, rows: &[Vec<u8>], filters: &[FilterType]) -> Vec<u8> {
+        let mut filtered: Vec<u8> = Vec::new();
+        let mut prior: Vec<u8> = vec![0u8; rows.get(0).map_or(0, |row| row.len())];
+
+        for (y, row) in rows.iter().enumerate() {
+            let filter = filters[y % filters.len()];
+            filtered.push(filter.into());
+
+            for x in 0..row.len() {
+                let a = if x >= bpp { row[x - bpp] } else { 0 };
+                let b = prior[x];
+                let c = if x >= bpp { prior[x - bpp] } else { 0 };
+
+                let predicted = match filter {
+                    FilterType::None => 0,
+                    FilterType::Sub => a,
+                    FilterType::Up => b,
+                    FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+                    FilterType::Paeth => paeth_predictor(a, b, c),
+                };
+
+                filtered.push(row[x].wrapping_sub(predicted));
+            }
+
+            prior = row.clone();
+        }
+
+        filtered
+    }
+
+    /// Packs samples below 8 bits most significant bit first, 8-bit samples are copied.
+    fn pack(samples: &[u8], bitdepth: usize) -> Vec<u8> {
+        if bitdepth == 8 {
+            return samples.to_vec();
+        }
+
+        let mut packed: Vec<u8> = vec![0u8; (samples.len() * bitdepth + 7) / 8];
+        for (i, sample) in samples.iter().enumerate() {
+            let bit = i * bitdepth;
+            packed[bit / 8] |= sample << (8 - bitdepth - bit % 8);
+        }
+        packed
+    }
+
+    /// A `width` x `height` image of pseudo-random samples below `2^bitdepth`,
+    /// one row of `samples` samples per pixel each.
+    fn test_image(width: usize, height: usize, samples: usize, bitdepth: usize) -> Vec<Vec<u8>> {
+        let mut state = 0x2545f491u32;
+
+        (0..height).map(|_| {
+            (0..width * samples).map(|_| {
+                // xorshift32
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8 >> (8 - bitdepth)
+            }).collect()
+        }).collect()
+    }
+
+    /// The non-empty Adam7 passes of `image`, each packed and filtered on its own.
+    fn interlace(image: &[Vec<u8>], samples: usize, bitdepth: usize, filters: &[FilterType]) -> Vec<u8> {
+        let height = image.len();
+        let width = image[0].len() / samples;
+        let bpp = cmp::max(1, samples * bitdepth / 8);
+
+        let mut data: Vec<u8> = Vec::new();
+
+        for &(x0, y0, dx, dy) in ADAM7_PASSES.iter() {
+            let rows: Vec<Vec<u8>> = (y0 as usize..height).step_by(dy as usize).map(|y| {
+                let pass_samples: Vec<u8> = (x0 as usize..width).step_by(dx as usize)
+                    .flat_map(|x| image[y][x * samples..(x + 1) * samples].to_vec())
+                    .collect();
+                pack(&pass_samples, bitdepth)
+            }).collect();
+
+            if rows.len() > 0 && rows[0].len() > 0 {
+                data.extend(filter_rows(bpp, &rows, filters));
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn each_adam7_pass_is_unfiltered_on_its_own() {
+        let filters = [FilterType::Up, FilterType::Paeth, FilterType::Average, FilterType::Sub];
+
+        for &(color, samples) in [(0u8, 1usize), (2, 3), (6, 4)].iter() {
+            let image = test_image(13, 11, samples, 8);
+            let header = header(13, 11, 8, color, 1);
+
+            let pixels = deinterlace(&header, &interlace(&image, samples, 8, &filters)).unwrap();
+            assert_eq!(pixels, image.concat());
+        }
+    }
+
+    #[test]
+    fn interlaced_files_match_their_twins() {
+        let filters = [FilterType::Sub, FilterType::Up, FilterType::Average, FilterType::Paeth, FilterType::None];
+        let formats = [(0u8, 1usize, 1usize), (0, 1, 2), (0, 1, 4), (0, 1, 8), (2, 3, 8)];
+
+        for &(color, samples, bitdepth) in formats.iter() {
+            for &(width, height) in [(1usize, 1usize), (3, 5), (13, 11), (17, 9)].iter() {
+                let image = test_image(width, height, samples, bitdepth);
+                let rows: Vec<Vec<u8>> = image.iter().map(|row| pack(row, bitdepth)).collect();
+                let bpp = cmp::max(1, samples * bitdepth / 8);
+
+                let mut pixels: Vec<Vec<u8>> = Vec::new();
+
+                for &(interlace_method, ref data) in [(0u8, filter_rows(bpp, &rows, &filters)),
+                                                      (1, interlace(&image, samples, bitdepth, &filters))].iter() {
+                    let file = png(&[
+                        (ChunkKind::IHDR, &header_data(width as u32, height as u32, bitdepth as u8, color, interlace_method)),
+                        (ChunkKind::IDAT, &zlib(data)),
+                        (ChunkKind::IEND, &[]),
+                    ]);
+
+                    let mut decoder = Decoder::new(Cursor::new(file));
+                    let data = decoder.read_image().unwrap();
+                    let header = decoder.header().unwrap();
+
+                    pixels.push(match header.interlace_method {
+                        Interlace::None => unfilter(&header, &data).unwrap(),
+                        Interlace::Adam7 => deinterlace(&header, &data).unwrap(),
+                    });
+                }
+
+                assert_eq!(pixels[0], rows.concat(), "{}x{} {}-bit color {}", width, height, bitdepth, color);
+                assert_eq!(pixels[1], pixels[0], "{}x{} {}-bit color {}", width, height, bitdepth, color);
+            }
+        }
+    }
+
+    #[test]
+    fn deinterlace_needs_adam7() {
+        let (filtered, _) = rgb_2x2();
+        match deinterlace(&header(2, 2, 8, 2, 0), &filtered) {
+            Err(Error::Format("not an Adam7 interlaced image")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn zero_length_idat_adds_nothing() {
+        let (filtered, pixels) = rgb_2x2();
+        let stream = zlib(&filtered);
+        let (first, second) = stream.split_at(stream.len() / 2);
+
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, first),
+            (ChunkKind::IDAT, &[]),
+            (ChunkKind::IDAT, second),
+            (ChunkKind::IDAT, &[]),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        // the empty chunks carry a valid CRC over just the type
+        let mut decoder = Decoder::new(Cursor::new(file));
+        let data = decoder.read_image().unwrap();
+        assert_eq!(unfilter(&decoder.header().unwrap(), &data).unwrap(), pixels);
+
+        let mut samples: Vec<u8> = Vec::new();
+        decoder.for_each_pixel(|_, _, pixel| samples.extend_from_slice(pixel)).unwrap();
+        assert_eq!(samples, pixels);
+
+        let idats = decoder.idat_chunks().unwrap();
+        assert_eq!(idats.iter().map(|chunk| chunk.length).collect::<Vec<u32>>(),
+                   vec![first.len() as u32, 0, second.len() as u32, 0]);
+
+        let mut reader = IdatReader { handle: &mut decoder.handle, chunks: idats, index: 0, consumed: 0 };
+        assert_eq!(reader.read(&mut []).unwrap(), 0);
+
+        let mut data: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, stream);
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    fn simd_unfilter_matches_scalar() {
+        let filters = [FilterType::None, FilterType::Sub, FilterType::Up, FilterType::Average, FilterType::Paeth];
+
+        for &filter in filters.iter() {
+            for bpp in 1..9 {
+                // 37 pixels: whole 16-byte blocks and a tail for every pixel size
+                let rows = test_image(37, 4, bpp, 8);
+                let stride = rows[0].len();
+                let data = filter_rows(bpp, &rows, &[filter]);
+
+                let mut scalar: Vec<u8> = vec![0u8; stride * rows.len()];
+                let zeros: Vec<u8> = vec![0u8; stride];
+                for (y, filtered) in data.chunks(stride + 1).enumerate() {
+                    let (done, rest) = scalar.split_at_mut(y * stride);
+                    let prior: &[u8] = if y == 0 { &zeros } else { &done[(y - 1) * stride..] };
+                    unfilter_row_scalar(filter, bpp, prior, &filtered[1..], &mut rest[..stride]);
+                }
+
+                let pixels = unfilter_scanlines(bpp, stride, rows.len(), &data).unwrap();
+                assert_eq!(pixels, scalar, "{:?} at {} bytes per pixel", filter, bpp);
+                assert_eq!(pixels, rows.concat(), "{:?} at {} bytes per pixel", filter, bpp);
+            }
+        }
+    }
+
+    /// A handle of `length` bytes that are zero except for `parts`, so chunk offsets past
+    /// 4 GiB can be tested without the memory.
+    #[derive(Clone)]
+    struct Sparse {
+        pos: u64,
+        length: u64,
+        parts: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl Read for Sparse {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let amt = cmp::min(buf.len() as u64, self.length.saturating_sub(self.pos)) as usize;
+
+            for (i, byte) in buf[..amt].iter_mut().enumerate() {
+                let pos = self.pos + i as u64;
+                *byte = self.parts.iter()
+                                  .find(|&&(offset, ref data)| pos >= offset && pos < offset + data.len() as u64)
+                                  .map_or(0, |&(offset, ref data)| data[(pos - offset) as usize]);
+            }
+
+            self.pos += amt as u64;
+            Ok(amt)
+        }
+    }
+
+    impl Seek for Sparse {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(pos) => pos,
+                SeekFrom::Current(delta) => (self.pos as i64 + delta) as u64,
+                SeekFrom::End(delta) => (self.length as i64 + delta) as u64,
+            };
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    fn chunks_past_4_gib() {
+        let head = png(&[(ChunkKind::IHDR, &header_data(1, 1, 8, 0, 0))]);
+        let mut pos = head.len() as u64;
+        let mut parts: Vec<(u64, Vec<u8>)> = Vec::new();
+
+        // three chunks of the largest legal length, their zero CRCs are not checked
+        let length = i32::max_value() as u32;
+        for _ in 0..3 {
+            let mut chunk_header: Vec<u8> = Vec::new();
+            chunk_header.write_u32::<NetworkEndian>(length).unwrap();
+            chunk_header.extend_from_slice(b"zTXt");
+            parts.push((pos, chunk_header));
+            pos += 12 + length as u64;
+        }
+
+        let idat_offset = pos + 8;
+        let mut tail: Vec<u8> = Vec::new();
+        write_chunk(&mut tail, ChunkKind::IDAT, &[]).unwrap();
+        write_chunk(&mut tail, ChunkKind::IEND, &[]).unwrap();
+        parts.push((pos, tail));
+        pos += 24;
+
+        parts.push((0, head));
+
+        let mut decoder = Decoder::new(Sparse { pos: 0, length: pos, parts: parts });
+        decoder.set_crc_validation(false);
+
+        let summary = decoder.summary().unwrap();
+        assert_eq!(summary.chunk_count, 6);
+        assert_eq!(summary.idat_bytes, 0);
+
+        let mut decoder = Decoder::new(decoder.into_inner());
+        decoder.set_crc_validation(false);
+        decoder.handle.seek(SeekFrom::Start(0)).unwrap();
+        decoder.read_signature().unwrap();
+
+        let idat = decoder.skip_to(ChunkKind::IDAT).unwrap().unwrap();
+        assert_eq!(idat.offset, idat_offset);
+        assert!(idat.offset > 1 << 32);
+        assert_eq!(decoder.skip_to(ChunkKind::IEND).unwrap().unwrap().offset, idat_offset + 12);
+    }
+
+    #[test]
+    fn oversized_chunk_length_is_rejected() {
+        let mut file = SIGNATURE.to_vec();
+        file.write_u32::<NetworkEndian>(0x8000_0000).unwrap();
+        file.extend_from_slice(b"IHDR");
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_signature().unwrap();
+        match decoder.read_chunk() {
+            Err(Error::Format("invalid chunk length")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_header_reads_on_from_the_current_state() {
+        let file = png(&[(ChunkKind::IHDR, &header_data(3, 2, 8, 2, 0)), (ChunkKind::IEND, &[])]);
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        assert_eq!(decoder.read_header().unwrap(), header(3, 2, 8, 2, 0));
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_signature().unwrap();
+        assert_eq!(decoder.read_header().unwrap(), header(3, 2, 8, 2, 0));
+        // cached, the next chunk is still IEND
+        assert_eq!(decoder.read_header().unwrap(), header(3, 2, 8, 2, 0));
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IEND);
+    }
+
+    #[test]
+    fn read_header_passes_over_cgbi() {
+        let file = png(&[(ChunkKind::CgBI, &[0x50, 0x00, 0x20, 0x02]),
+                         (ChunkKind::IHDR, &header_data(1, 1, 8, 6, 0)),
+                         (ChunkKind::IEND, &[])]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        assert_eq!(decoder.read_header().unwrap(), header(1, 1, 8, 6, 0));
+        assert!(decoder.is_cgbi());
+    }
+
+    #[test]
+    fn read_header_stops_at_other_chunks() {
+        let file = png(&[(ChunkKind::tEXt, b"Comment\0hello"),
+                         (ChunkKind::IHDR, &header_data(1, 1, 8, 0, 0)),
+                         (ChunkKind::IEND, &[])]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        match decoder.read_header() {
+            Err(Error::Format("missing IHDR chunk")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        let file = png(&[(ChunkKind::IHDR, &header_data(0, 1, 8, 0, 0)), (ChunkKind::IEND, &[])]);
+        match Decoder::new(Cursor::new(file)).read_header() {
+            Err(Error::Format("invalid image dimensions")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn strip_metadata_keeps_the_image() {
+        let (filtered, pixels) = rgb_2x2();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Comment\0hello"),
+            (ChunkKind::bKGD, &[0, 1, 0, 2, 0, 3]),
+            (ChunkKind::tIME, &[0x07, 0xea, 10, 16, 12, 0, 0]),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut stripped: Vec<u8> = Vec::new();
+        strip_metadata(Cursor::new(file), &mut stripped).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(stripped.clone()));
+        let mut kinds: Vec<ChunkKind> = Vec::new();
+        decoder.read_chunks_with(|chunk| kinds.push(chunk.kind)).unwrap();
+        assert_eq!(kinds, vec![ChunkKind::IHDR, ChunkKind::bKGD, ChunkKind::IDAT, ChunkKind::IEND]);
+
+        let mut decoder = Decoder::new(Cursor::new(stripped));
+        let data = decoder.read_image().unwrap();
+        assert_eq!(unfilter(&decoder.header().unwrap(), &data).unwrap(), pixels);
+    }
+
+    #[test]
+    fn strip_metadata_checks_crcs() {
+        let mut file = rgb_2x2_png();
+        let len = file.len();
+        // last byte of the IDAT CRC, IEND is 12 bytes
+        file[len - 13] ^= 0xff;
+
+        match strip_metadata(Cursor::new(file), Vec::new()) {
+            Err(Error::CrcMismatch { chunk_kind: ChunkKind::IDAT, .. }) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn forward_only_decode_from_a_pipe() {
+        let (filtered, _) = rgb_2x2();
+
+        // `&[u8]` reads but can't seek, like stdin
+        let file = rgb_2x2_png();
+        let mut decoder = Decoder::new(ForwardOnly::new(&file[..]));
+
+        assert_eq!(decoder.read_signature().unwrap(), SIGNATURE);
+        assert_eq!(decoder.read_signature().unwrap(), SIGNATURE);
+        assert_eq!(decoder.read_header().unwrap(), header(2, 2, 8, 2, 0));
+        assert_eq!(decoder.read_image_forward().unwrap(), filtered);
+
+        // skipped chunks are read over
+        let mut decoder = Decoder::new(ForwardOnly::new(&file[..]));
+        decoder.set_skip_ancillary(true);
+        decoder.set_crc_validation(false);
+        assert_eq!(decoder.read_image_forward().unwrap(), filtered);
+
+        // going back is refused, not silently wrong
+        let mut decoder = Decoder::new(ForwardOnly::new(&file[..]));
+        decoder.read_header().unwrap();
+        match decoder.read_image() {
+            Err(Error::IoError(_)) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn cgbi_decodes_to_rgba() {
+        // premultiplied BGRA: opaque, transparent, and 20% alpha
+        let stored: Vec<u8> = vec![200, 10, 255, 255, 0, 0, 0, 0, 10, 20, 51, 51];
+        let rgba: Vec<u8> = vec![255, 10, 200, 255, 0, 0, 0, 0, 255, 100, 50, 51];
+
+        // raw deflate, no zlib header
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&[0u8]).unwrap();
+        encoder.write_all(&stored).unwrap();
+
+        let file = png(&[
+            (ChunkKind::CgBI, &[0x50, 0x00, 0x20, 0x02]),
+            (ChunkKind::IHDR, &header_data(3, 1, 8, 6, 0)),
+            (ChunkKind::IDAT, &encoder.finish().unwrap()),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        let mut pixels = unfilter(&decoder.read_header().unwrap(), &decoder.read_image().unwrap()).unwrap();
+        assert!(decoder.is_cgbi());
+        cgbi_to_rgba(&mut pixels);
+        assert_eq!(pixels, rgba);
+
+        let mut samples: Vec<u8> = Vec::new();
+        decoder.for_each_pixel(|_, _, pixel| samples.extend_from_slice(pixel)).unwrap();
+        assert_eq!(samples, rgba);
+    }
+
+    #[test]
+    fn pixel_budget_is_checked_before_inflating() {
+        let (filtered, _) = rgb_2x2();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(65535, 65535, 8, 6, 0)),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        match decoder.read_image() {
+            Err(Error::Format("image exceeds the pixel budget")) => { },
+            other => panic!("{:?}", other),
+        }
+        match decoder.for_each_pixel(|_, _, _| { }) {
+            Err(Error::Format("image exceeds the pixel budget")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+        decoder.set_max_pixels(3);
+        assert!(decoder.read_image().is_err());
+        decoder.set_max_pixels(4);
+        assert_eq!(decoder.read_image().unwrap(), filtered);
+    }
+
+    #[test]
+    fn rgba8_size_does_not_wrap() {
+        let size = header(65535, 65535, 8, 6, 0).rgba8_size();
+
+        if cfg!(target_pointer_width = "32") {
+            match size {
+                Err(Error::Format("image too large")) => { },
+                other => panic!("{:?}", other),
             }
-        } else if self.state == State::Chunk(ChunkKind::IEND) {
-            None
         } else {
-            if let Ok(chunk) = self.read_chunk() {
-                Some(Element::Chunk(chunk))
+            assert_eq!(size.unwrap() as u64, 65535u64 * 65535 * 4);
+        }
+    }
+
+    #[test]
+    fn stereo_pair_is_split_into_equal_halves() {
+        for &(mode, layout) in [(0u8, StereoLayout::CrossFuse), (1, StereoLayout::DivergingFuse)].iter() {
+            // 3 pixel subimages, 5 pixels of padding to reach the byte boundary
+            let row: Vec<u8> = vec![1, 2, 3, 0, 0, 0, 0, 0, 7, 8, 9];
+            let filtered = filter_rows(1, &[row.clone(), row], &[FilterType::None]);
+            let file = png(&[
+                (ChunkKind::IHDR, &header_data(11, 2, 8, 0, 0)),
+                (ChunkKind::sTER, &[mode]),
+                (ChunkKind::IDAT, &zlib(&filtered)),
+                (ChunkKind::IEND, &[]),
+            ]);
+
+            let mut decoder = Decoder::new(Cursor::new(file));
+            let header = decoder.read_header().unwrap();
+            let chunk = decoder.skip_to(ChunkKind::sTER).unwrap().unwrap();
+            assert_eq!(decoder.read_stereo_layout(&chunk).unwrap(), layout);
+
+            let pixels = unfilter(&header, &decoder.read_image().unwrap()).unwrap();
+            let (left_eye, right_eye) = split_stereo(&header, layout, &pixels).unwrap();
+
+            let (left_half, right_half) = (vec![1, 2, 3, 1, 2, 3], vec![7, 8, 9, 7, 8, 9]);
+            if layout == StereoLayout::CrossFuse {
+                assert_eq!((left_eye, right_eye), (right_half, left_half));
             } else {
-                None
+                assert_eq!((left_eye, right_eye), (left_half, right_half));
             }
         }
+
+        // 1-bit: 0b101 then padding, the right half starts on the second byte
+        let header = header(11, 1, 1, 0, 0);
+        let (left, right) = split_stereo(&header, StereoLayout::DivergingFuse, &[0b1011_1111, 0b0110_0000]).unwrap();
+        assert_eq!((left, right), (vec![0b1010_0000], vec![0b0110_0000]));
+
+        let mut decoder = Decoder::new(Cursor::new(png(&[(ChunkKind::sTER, &[2])])));
+        decoder.read_signature().unwrap();
+        let chunk = decoder.read_chunk().unwrap();
+        match decoder.read_stereo_layout(&chunk) {
+            Err(Error::Format("invalid sTER mode")) => { },
+            other => panic!("{:?}", other),
+        }
     }
-}
 
+    #[test]
+    fn color_and_depth_descriptions() {
+        assert_eq!(header(1, 1, 8, 6, 0).color_and_depth_description(), "8-bit RGBA");
+        assert_eq!(header(1, 1, 16, 2, 0).color_and_depth_description(), "16-bit RGB");
+        assert_eq!(header(1, 1, 1, 3, 0).color_and_depth_description(), "1-bit Indexed");
+        assert_eq!(header(1, 1, 4, 0, 0).color_and_depth_description(), "4-bit Grayscale");
+        assert_eq!(header(1, 1, 8, 4, 0).color_and_depth_description(), "8-bit Grayscale Alpha");
+    }
 
+    #[test]
+    fn idat_byte_range_spans_every_idat() {
+        let (filtered, _) = rgb_2x2();
+        let stream = zlib(&filtered);
+        let (first, rest) = stream.split_at(4);
+        let (second, third) = rest.split_at(3);
 
-fn main(){
-    let core = num_cpus::get_physical();
-    let threads = num_cpus::get();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, first),
+            (ChunkKind::IDAT, second),
+            (ChunkKind::IDAT, third),
+            (ChunkKind::IEND, &[]),
+        ]);
 
-    println!("Core: {:?} Threads: {:?}", core, threads);
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        decoder.read_chunks_with(|chunk| chunks.push(*chunk)).unwrap();
 
-    // let filepath = "/Users/luozijun/Pictures/qmshtu.png";
-    let filepath = "output.png";
+        // signature, IHDR of 25 bytes, then the data of the first IDAT
+        let start = 8 + 25 + 8;
+        let end = start + first.len() + 12 + second.len() + 12 + third.len();
+        assert_eq!(idat_byte_range(&chunks), Some((start as u64, (end - start) as u64)));
+        assert_eq!(&file[start..start + 4], first);
+        assert_eq!(&file[end - third.len()..end], third);
 
-    let mut file = File::open(filepath).unwrap();
+        assert_eq!(idat_byte_range(&chunks[..1]), None);
+    }
 
-    let decoder = Decoder::new(file.try_clone().unwrap());
-    let chunks: Vec<Chunk> = decoder.filter(|elem| elem.is_chunk())
-                                    .map(|elem| elem.chunk())
-                                    .collect::<Vec<Chunk>>();
+    #[test]
+    fn streamed_crc_matches_a_one_shot_crc() {
+        let payload: Vec<u8> = (0..CHUNK_BUFFER_SIZE * 2 + 123).map(|i| (i * 31 % 251) as u8).collect();
 
-    pub const CHUNCK_BUFFER_SIZE: usize = 4 * 1024;
+        let mut typed = b"IDAT".to_vec();
+        typed.extend_from_slice(&payload);
 
-    let mut buffer: [u8; CHUNCK_BUFFER_SIZE] = [0u8; CHUNCK_BUFFER_SIZE];
-    let mut zlib_decoder = flate2::write::ZlibDecoder::new(Vec::new());
+        let crc = stream_crc(&mut &payload[..], b"IDAT", payload.len() as u32).unwrap();
+        assert_eq!(crc, crc32::checksum_ieee(&typed));
 
-    let now = Instant::now();
+        // a short payload is an error, not a CRC over fewer bytes
+        assert!(stream_crc(&mut &payload[..10], b"IDAT", 11).is_err());
+    }
 
-    let mut header: Option<Header> = None;
+    #[test]
+    fn filter_none_fast_path_matches_the_general_path() {
+        for &(samples, bitdepth) in [(3usize, 8usize), (4, 8), (1, 2)].iter() {
+            let rows: Vec<Vec<u8>> = test_image(21, 7, samples, bitdepth).iter().map(|row| pack(row, bitdepth)).collect();
+            let bpp = cmp::max(1, samples * bitdepth / 8);
+            let stride = rows[0].len();
+            let data = filter_rows(bpp, &rows, &[FilterType::None]);
 
-    for chunk in chunks.iter() {
+            let fast = unfilter_scanlines(bpp, stride, rows.len(), &data).unwrap();
+            assert_eq!(fast, unfilter_each_row(bpp, stride, &data).unwrap());
+            assert_eq!(fast, rows.concat());
+        }
+    }
 
-        if chunk.kind == ChunkKind::IHDR {
-            assert_eq!(header.is_none(), true);
+    #[test]
+    fn palette_chunk_round_trip() {
+        let entries: Vec<u8> = vec![255, 0, 0, 0, 255, 0, 0, 0, 255];
 
-            file.seek(SeekFrom::Start(chunk.offset)).unwrap();
-            
-            pub const HEADER_SIZE: u32 = 13u32;
+        let mut chunk: Vec<u8> = Vec::new();
+        write_chunk(&mut chunk, ChunkKind::PLTE, &entries).unwrap();
 
-            assert_eq!(chunk.length, HEADER_SIZE as u32);
+        assert_eq!(&chunk[..4], &[0, 0, 0, 9]);
+        assert_eq!(&chunk[4..8], b"PLTE");
+        assert_eq!(&chunk[8..17], &entries[..]);
+        assert_eq!(NetworkEndian::read_u32(&chunk[17..]), crc32::checksum_ieee(&chunk[4..17]));
 
-            let width: u32 = file.read_u32::<NetworkEndian>().unwrap();
-            let height: u32 = file.read_u32::<NetworkEndian>().unwrap();
+        let mut file = png(&[(ChunkKind::IHDR, &header_data(1, 1, 2, 3, 0))]);
+        file.extend(chunk);
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_header().unwrap();
 
-            assert_eq!(file.read(&mut buffer[..1]).unwrap(), 1);
-            let bitdepth: BitDepth = BitDepth::try_from(buffer[0]).unwrap();
+        let chunk = decoder.read_chunk().unwrap();
+        assert_eq!((chunk.kind, chunk.length), (ChunkKind::PLTE, 9));
+        assert_eq!(decoder.read_palette(&chunk).unwrap(), Palette(vec![[255, 0, 0], [0, 255, 0], [0, 0, 255]]));
+    }
+
+    #[test]
+    fn iterating_by_reference_keeps_the_decoder() {
+        let file = rgb_2x2_png();
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+
+        let mut kinds: Vec<ChunkKind> = Vec::new();
+        for elem in &mut decoder {
+            if elem.is_chunk() {
+                kinds.push(elem.chunk().kind);
+            }
+        }
 
-            assert_eq!(file.read(&mut buffer[..1]).unwrap(), 1);
-            let color: Color = Color::try_from(buffer[0]).unwrap();
+        assert_eq!(kinds, vec![ChunkKind::IHDR, ChunkKind::tEXt, ChunkKind::IDAT, ChunkKind::IEND]);
+        assert_eq!(decoder.header(), Some(header(2, 2, 8, 2, 0)));
+        assert_eq!(decoder.into_inner().into_inner(), file);
+    }
 
-            assert_eq!(file.read(&mut buffer[..1]).unwrap(), 1);
-            let compression_method = buffer[0];
+    #[test]
+    fn skip_to_passes_leading_ancillary_chunks() {
+        let (filtered, _) = rgb_2x2();
+        let idat = zlib(&filtered);
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Comment\0hello"),
+            (ChunkKind::gAMA, &[0, 0, 0xb1, 0x8f]),
+            (ChunkKind::pHYs, &[0, 0, 0x0b, 0x13, 0, 0, 0x0b, 0x13, 1]),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]);
 
-            assert_eq!(file.read(&mut buffer[..1]).unwrap(), 1);
-            let filter_method = buffer[0];
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_signature().unwrap();
 
-            assert_eq!(file.read(&mut buffer[..1]).unwrap(), 1);
-            let interlace_method = buffer[0];
-            
-            header = Some(Header {
-                width: width,
-                height: height,
-                bitdepth: bitdepth,
-                color: color,
-                compression_method: compression_method,
-                filter_method: filter_method,
-                interlace_method: interlace_method,
-            });
+        let chunk = decoder.skip_to(ChunkKind::IDAT).unwrap().unwrap();
+        assert_eq!((chunk.index, chunk.length), (4, idat.len() as u32));
+        assert_eq!(decoder.read_chunk_data(&chunk).unwrap(), idat);
 
-            continue;
+        // IEND comes before another IDAT
+        assert_eq!(decoder.skip_to(ChunkKind::IDAT).unwrap(), None);
+    }
+
+    #[test]
+    fn color_predicates() {
+        let cases = [
+            (Color::Greyscale, false, true, false),
+            (Color::Truecolour, false, false, false),
+            (Color::Indexed, false, false, true),
+            (Color::GreyscaleWithAlpha, true, true, false),
+            (Color::TruecolourWithAlpha, true, false, false),
+        ];
+
+        for &(color, has_alpha, is_grayscale, is_indexed) in cases.iter() {
+            assert_eq!(color.has_alpha(), has_alpha, "{:?}", color);
+            assert_eq!(color.is_grayscale(), is_grayscale, "{:?}", color);
+            assert_eq!(color.is_indexed(), is_indexed, "{:?}", color);
+        }
+    }
+
+    #[test]
+    fn fingerprint_follows_the_chunks() {
+        let fingerprint = |file: Vec<u8>| Decoder::new(Cursor::new(file)).fingerprint().unwrap();
+
+        let (filtered, _) = rgb_2x2();
+        let edited = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Comment\0bye"),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        assert_eq!(fingerprint(rgb_2x2_png()), fingerprint(rgb_2x2_png()));
+        assert_ne!(fingerprint(rgb_2x2_png()), fingerprint(edited));
+    }
+
+    #[test]
+    fn tiny_interlaced_images_skip_empty_passes() {
+        let filters = [FilterType::Paeth, FilterType::Sub];
+
+        for &size in [1u32, 2, 3, 5].iter() {
+            let passes = adam7_pass_dimensions(size, size);
+            let total: u32 = passes.iter().map(|&(w, h)| w * h).sum();
+            assert_eq!(total, size * size);
+
+            let image = test_image(size as usize, size as usize, 3, 8);
+            let file = png(&[
+                (ChunkKind::IHDR, &header_data(size, size, 8, 2, 1)),
+                (ChunkKind::IDAT, &zlib(&interlace(&image, 3, 8, &filters))),
+                (ChunkKind::IEND, &[]),
+            ]);
+
+            let mut decoder = Decoder::new(Cursor::new(file));
+            let data = decoder.read_image().unwrap();
+            let header = decoder.header().unwrap();
+
+            assert_eq!(deinterlace(&header, &data).unwrap(), image.concat(), "{}x{}", size, size);
+        }
+    }
+
+    #[test]
+    fn gif_graphic_control_fields() {
+        let (filtered, _) = rgb_2x2();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::gIFg, &[2, 1, 0x01, 0x2c]),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_header().unwrap();
+
+        let chunk = decoder.read_chunk().unwrap();
+        assert_eq!(chunk.kind, ChunkKind::gIFg);
+        assert_eq!(decoder.read_gif_graphic_control(&chunk).unwrap(), GifGraphicControl {
+            disposal_method: 2,
+            user_input: true,
+            delay_time: 300,
+        });
+
+        // the opaque chunk does not end iteration
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IDAT);
+    }
+
+    #[test]
+    fn skip_ancillary_reads_only_what_composes_pixels() {
+        let (filtered, pixels) = rgb_2x2();
+        let mut file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Comment\0hello"),
+            (ChunkKind::bKGD, &[0, 1, 0, 2, 0, 3]),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+        // a tEXt payload that was read would fail its CRC
+        file[8 + 25 + 8] ^= 0xff;
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_skip_ancillary(true);
+
+        let kinds: Vec<ChunkKind> = (&mut decoder).filter(|elem| elem.is_chunk())
+                                                  .map(|elem| elem.chunk().kind)
+                                                  .collect();
+        assert_eq!(kinds, vec![ChunkKind::IHDR, ChunkKind::bKGD, ChunkKind::IDAT, ChunkKind::IEND]);
+        assert_eq!(decoder.read_image().unwrap(), filtered);
+        assert_eq!(unfilter(&decoder.header().unwrap(), &filtered).unwrap(), pixels);
+    }
+
+    #[test]
+    fn duplicate_critical_chunks_strict_and_lenient() {
+        let (filtered, _) = rgb_2x2();
+        let idat = zlib(&filtered);
+
+        let twice_ihdr = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IHDR, &header_data(4, 4, 8, 2, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(twice_ihdr));
+        decoder.read_header().unwrap();
+        match decoder.read_chunk() {
+            Err(Error::Format("duplicate critical chunk")) => { },
+            other => panic!("{:?}", other),
         }
 
-        if chunk.kind != ChunkKind::IDAT {
-            continue
+        let twice_plte = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::PLTE, &[1, 2, 3]),
+            (ChunkKind::PLTE, &[4, 5, 6, 7, 8, 9]),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(twice_plte));
+        decoder.set_strict(false);
+        decoder.read_header().unwrap();
+
+        let chunk = decoder.read_chunk().unwrap();
+        assert_eq!(decoder.read_palette(&chunk).unwrap(), Palette(vec![[1, 2, 3]]));
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IDAT);
+        assert_eq!(decoder.warnings(), &[Warning::DuplicateChunk(ChunkKind::PLTE)]);
+    }
+
+    #[test]
+    fn junk_before_the_signature_is_lenient_only() {
+        let (_, pixels) = rgb_2x2();
+        let mut file: Vec<u8> = vec![0xef, 0xbb, 0xbf];
+        file.extend(rgb_2x2_png());
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        match decoder.read_signature() {
+            Err(Error::InvalidSignature) => { },
+            other => panic!("{:?}", other),
         }
-        
-        println!("{:?}", chunk);
 
-        let size = chunk.length as u64;
-        let mut readed = 0u64;
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_strict(false);
+        assert_eq!(decoder.read_signature().unwrap(), SIGNATURE);
+        assert_eq!(decoder.warnings(), &[Warning::JunkBeforeSignature(3)]);
 
-        file.seek(SeekFrom::Start(chunk.offset)).unwrap();
+        let data = decoder.read_image().unwrap();
+        assert_eq!(unfilter(&decoder.header().unwrap(), &data).unwrap(), pixels);
 
-        while readed < size {
-            let amt = file.read(&mut buffer).unwrap();
-            
-            if amt == 0 {
-                break;
+        // the scan gives up after SIGNATURE_SCAN_LIMIT bytes
+        let mut file: Vec<u8> = vec![b' '; SIGNATURE_SCAN_LIMIT + 1];
+        file.extend(rgb_2x2_png());
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.set_strict(false);
+        match decoder.read_signature() {
+            Err(Error::InvalidSignature) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn text_by_keyword_reads_every_text_chunk_kind() {
+        let (filtered, _) = rgb_2x2();
+
+        let mut ztxt = b"Title\0\0".to_vec();
+        ztxt.extend(zlib(b"compressed"));
+        let mut itxt = b"Title\0\x01\0en\0Titel\0".to_vec();
+        itxt.extend(zlib("unicode \u{2713}".as_bytes()));
+
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::tEXt, b"Title\0plain \xe9"),
+            (ChunkKind::tEXt, b"Author\0someone"),
+            (ChunkKind::zTXt, &ztxt),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::iTXt, &itxt),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        assert_eq!(decoder.text_by_keyword("Title").unwrap(),
+                   vec!["plain \u{e9}".to_string(), "compressed".to_string(), "unicode \u{2713}".to_string()]);
+        assert_eq!(decoder.text_by_keyword("Author").unwrap(), vec!["someone".to_string()]);
+        assert_eq!(decoder.text_by_keyword("title").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn max_text_size_caps_inflated_text() {
+        let mut ztxt = b"Comment\0\0".to_vec();
+        ztxt.extend(zlib(&[b'a'; 100]));
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 0, 0)),
+            (ChunkKind::zTXt, &ztxt),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_header().unwrap();
+        let chunk = decoder.read_chunk().unwrap();
+
+        decoder.set_max_text_size(99);
+        match decoder.read_text(&chunk) {
+            Err(Error::Format("text chunk too large")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        decoder.set_max_text_size(100);
+        assert_eq!(decoder.read_text(&chunk).unwrap().text, "a".repeat(100));
+    }
+
+    #[test]
+    fn xmp_packet_from_its_itxt_chunk() {
+        let packet = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>";
+        let mut itxt = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        itxt.extend(packet.as_bytes());
+
+        let (filtered, _) = rgb_2x2();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::iTXt, b"Comment\0\0\0\0\0not xmp"),
+            (ChunkKind::iTXt, &itxt),
+            (ChunkKind::IDAT, &zlib(&filtered)),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        assert_eq!(Decoder::new(Cursor::new(file)).xmp().unwrap(), Some(packet.to_string()));
+        assert_eq!(Decoder::new(Cursor::new(rgb_2x2_png())).xmp().unwrap(), None);
+    }
+
+    #[test]
+    fn transparency_from_the_color_type_or_trns() {
+        let transparency = |chunks: &[(ChunkKind, &[u8])]| {
+            Decoder::new(Cursor::new(png(chunks))).has_transparency().unwrap()
+        };
+        let idat = zlib(&[0, 0]);
+
+        assert!(transparency(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 6, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]));
+        assert!(transparency(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 4, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]));
+        assert!(transparency(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 3, 0)),
+            (ChunkKind::PLTE, &[0, 0, 0]),
+            (ChunkKind::tRNS, &[0]),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]));
+        assert!(!transparency(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 2, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]));
+        // tRNS only counts before the image data
+        assert!(!transparency(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 2, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::tRNS, &[0, 0, 0, 0, 0, 0]),
+            (ChunkKind::IEND, &[]),
+        ]));
+    }
+
+    #[test]
+    fn indexed_image_needs_a_palette() {
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 3, 0)),
+            (ChunkKind::IDAT, &zlib(&[0, 0])),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        match Decoder::new(Cursor::new(file)).read_image() {
+            Err(Error::Format("indexed image missing PLTE")) => { },
+            other => panic!("{:?}", other),
+        }
+
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(1, 1, 8, 3, 0)),
+            (ChunkKind::PLTE, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_header().unwrap();
+        match decoder.read_chunk() {
+            Err(Error::Format("invalid PLTE chunk")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn low_bit_grayscale_scales_to_the_full_range() {
+        for &bitdepth in [1usize, 2, 4].iter() {
+            let max = (1u8 << bitdepth) - 1;
+            let row: Vec<u8> = vec![0, max, max / 2 + 1, max / 2];
+            let scanline = [vec![0u8], pack(&row, bitdepth)].concat();
+
+            let file = png(&[
+                (ChunkKind::IHDR, &header_data(4, 1, bitdepth as u8, 0, 0)),
+                (ChunkKind::IDAT, &zlib(&scanline)),
+                (ChunkKind::IEND, &[]),
+            ]);
+
+            for &scale in [false, true].iter() {
+                let mut decoder = Decoder::new(Cursor::new(file.clone()));
+                decoder.set_scale_grayscale_to_8bit(scale);
+
+                let mut samples: Vec<u8> = Vec::new();
+                decoder.for_each_pixel(|_, _, pixel| samples.extend_from_slice(pixel)).unwrap();
+
+                let expected: Vec<u8> = if scale {
+                    row.iter().map(|&sample| (sample as u16 * 255 / max as u16) as u8).collect()
+                } else {
+                    row.clone()
+                };
+                assert_eq!(samples, expected, "{}-bit, scaled: {}", bitdepth, scale);
             }
+        }
+    }
+
+    #[test]
+    fn animation_control_and_frame_count() {
+        let (filtered, _) = rgb_2x2();
+        let idat = zlib(&filtered);
+
+        let apng = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::acTL, &[0, 0, 0, 3, 0, 0, 0, 0]),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(apng));
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(decoder.frame_count().unwrap(), 3);
 
-            zlib_decoder.write(&buffer[..amt]).unwrap();
+        decoder.reset().unwrap();
+        decoder.read_header().unwrap();
+        let chunk = decoder.read_chunk().unwrap();
+        assert_eq!(decoder.read_animation_control(&chunk).unwrap(), AnimationControl { num_frames: 3, num_plays: 0 });
 
-            let pixels = zlib_decoder.get_ref().len() as f64 / 3.0;
-            let ms = (now.elapsed().as_millis() as f64) / 1000.0;
-            let pps = pixels / ms;
-            println!("Chunk: {:?} decoded {:?} pixels  elapsed: {:?} seconds  PPS: {:?}", chunk.index, pixels, ms, pps);
-            readed += amt as u64;
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+        assert!(!decoder.is_animated().unwrap());
+        assert_eq!(decoder.frame_count().unwrap(), 1);
+
+        let broken = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::acTL, &[0, 0, 0, 0, 0, 0, 0, 0]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(broken));
+        decoder.read_header().unwrap();
+        let chunk = decoder.read_chunk().unwrap();
+        match decoder.read_animation_control(&chunk) {
+            Err(Error::Format("invalid acTL frame count")) => { },
+            other => panic!("{:?}", other),
         }
+
+        // acTL after the image data does not make an APNG
+        let late = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0)),
+            (ChunkKind::IDAT, &idat),
+            (ChunkKind::acTL, &[0, 0, 0, 3, 0, 0, 0, 0]),
+            (ChunkKind::IEND, &[]),
+        ]);
+        assert_eq!(Decoder::new(Cursor::new(late)).frame_count().unwrap(), 1);
     }
 
-    println!("{:?}", header);
-    let pixels = &zlib_decoder.finish().unwrap()[1..];
-    // println!("{:?}", pixels);
-    println!("Pixels: {:?} Bytes", pixels.len() );
-}
+    #[test]
+    fn cloned_decoders_continue_independently() {
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+        decoder.read_header().unwrap();
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::tEXt);
+
+        let mut clone = decoder.clone();
+        assert_eq!(clone.read_chunk().unwrap().kind, ChunkKind::IDAT);
+        assert_eq!(clone.read_chunk().unwrap().kind, ChunkKind::IEND);
+
+        assert_eq!(decoder.read_chunk().unwrap().kind, ChunkKind::IDAT);
+        assert_eq!(decoder.header(), clone.header());
+    }
+
+    #[test]
+    fn chunk_callback_sees_every_chunk_in_order() {
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+
+        let mut seen: Vec<(usize, ChunkKind)> = Vec::new();
+        let count = decoder.read_chunks_with(|chunk| seen.push((chunk.index, chunk.kind))).unwrap();
+
+        assert_eq!(count, 4);
+        assert_eq!(seen, vec![(0, ChunkKind::IHDR), (1, ChunkKind::tEXt), (2, ChunkKind::IDAT), (3, ChunkKind::IEND)]);
+
+        // only the chunks not read yet
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+        decoder.read_header().unwrap();
+        assert_eq!(decoder.read_chunks_with(|_| { }).unwrap(), 3);
+    }
+
+    #[test]
+    fn summary_of_a_representative_file() {
+        let image = test_image(3, 3, 3, 8);
+        let data = zlib(&interlace(&image, 3, 8, &[FilterType::Sub]));
+        let (first, second) = data.split_at(data.len() / 2);
+
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(3, 3, 8, 2, 1)),
+            (ChunkKind::acTL, &[0, 0, 0, 1, 0, 0, 0, 0]),
+            (ChunkKind::tEXt, b"Comment\0one"),
+            (ChunkKind::tEXt, b"Comment\0two"),
+            (ChunkKind::IDAT, first),
+            (ChunkKind::IDAT, second),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let summary = Decoder::new(Cursor::new(file)).summary().unwrap();
+        assert_eq!(summary.header, header(3, 3, 8, 2, 1));
+        assert_eq!(summary.chunk_count, 7);
+        assert_eq!(summary.chunk_counts[&ChunkKind::tEXt], 2);
+        assert_eq!(summary.chunk_counts[&ChunkKind::IDAT], 2);
+        assert_eq!(summary.idat_bytes, data.len() as u64);
+        assert!(summary.interlaced && summary.animated);
+        assert!(!summary.contains(ChunkKind::PLTE));
+    }
+
+    #[test]
+    fn sixteen_bit_rgba_samples() {
+        let eight_bit = header(2, 2, 8, 6, 0);
+        let rows: Vec<Vec<u8>> = vec![
+            vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0,  0, 1, 0, 2, 0, 3, 0xff, 0xff],
+            vec![0xff, 0xff, 0, 0, 0x80, 0x00, 0x7f, 0xff,  1, 0, 2, 0, 3, 0, 4, 0],
+        ];
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(2, 2, 16, 6, 0)),
+            (ChunkKind::IDAT, &zlib(&filter_rows(8, &rows, &[FilterType::Paeth, FilterType::Average]))),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        let data = decoder.read_image().unwrap();
+        let header = decoder.header().unwrap();
+        assert_eq!(header.bytes_per_pixel(), 8);
+
+        let samples = rgba16_samples(&header, &unfilter(&header, &data).unwrap()).unwrap();
+        assert_eq!(samples.len(), 16);
+        // second pixel of the second row
+        assert_eq!(&samples[12..16], &[0x0100, 0x0200, 0x0300, 0x0400]);
+        assert_eq!(&samples[..4], &[0x1234, 0x5678, 0x9abc, 0xdef0]);
+
+        assert_eq!(samples16_to_8(&samples[8..12]), vec![0xff, 0x00, 0x80, 0x7f]);
+
+        match rgba16_samples(&eight_bit, &[0u8; 16]) {
+            Err(Error::Format("not a 16-bit RGBA image")) => { },
+            other => panic!("{:?}", other),
+        }
+        match rgba16_samples(&header, &[0u8; 31]) {
+            Err(Error::Format("not enough image data")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn expand_indexed_resolves_palette_and_alpha() {
+        let palette = Palette(vec![[10, 20, 30], [40, 50, 60], [70, 80, 90]]);
+        let header = header(3, 2, 2, 3, 0);
+        // 2-bit indices 0 1 2 and 2 1 0, padded to a byte per row
+        let indices = pack(&[0, 1, 2, 0], 2).into_iter().chain(pack(&[2, 1, 0, 0], 2)).collect::<Vec<u8>>();
+
+        let pixels = expand_indexed(&header, &indices, &palette, Some(&[0, 128])).unwrap();
+        assert_eq!(pixels, vec![
+            10, 20, 30, 0,    40, 50, 60, 128,  70, 80, 90, 255,
+            70, 80, 90, 255,  40, 50, 60, 128,  10, 20, 30, 0,
+        ]);
+
+        // index 3 is past the end of the palette
+        let indices = pack(&[0, 3, 0, 0], 2).into_iter().chain(pack(&[0, 0, 0, 0], 2)).collect::<Vec<u8>>();
+        match expand_indexed(&header, &indices, &palette, None) {
+            Err(Error::Format("palette index out of range")) => { },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn parallel_crc_check_agrees_with_the_serial_one() {
+        let (filtered, _) = rgb_2x2();
+        let mut file = png(&[(ChunkKind::IHDR, &header_data(2, 2, 8, 2, 0))]);
+        let mut payloads: Vec<usize> = Vec::new();
+
+        for n in 0..40 {
+            payloads.push(file.len() + 8);
+            write_chunk(&mut file, ChunkKind::tEXt, format!("Comment\0{}", n).as_bytes()).unwrap();
+        }
+        write_chunk(&mut file, ChunkKind::IDAT, &zlib(&filtered)).unwrap();
+        write_chunk(&mut file, ChunkKind::IEND, &[]).unwrap();
+
+        let path = env::temp_dir().join(format!("png-parallel-crc-{}.png", process::id()));
+        fs::write(&path, &file).unwrap();
+        assert!(validate_crcs_parallel(&path).is_ok());
+
+        // both report the earlier of two damaged chunks
+        file[payloads[30]] ^= 0xff;
+        file[payloads[17]] ^= 0xff;
+        let stored = file[payloads[17] + 10..payloads[17] + 14].to_vec();
+        fs::write(&path, &file).unwrap();
+
+        let parallel = validate_crcs_parallel(&path);
+        fs::remove_file(&path).unwrap();
+        let serial = Decoder::new(Cursor::new(file)).read_chunks_with(|_| { });
+
+        match (parallel, serial) {
+            (Err(Error::CrcMismatch { crc_val: a, crc_sum: b, chunk_kind: ChunkKind::tEXt, .. }),
+             Err(Error::CrcMismatch { crc_val: c, crc_sum: d, chunk_kind: ChunkKind::tEXt, .. })) => {
+                assert_eq!((a, b), (c, d));
+                assert_eq!(a, NetworkEndian::read_u32(&stored));
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn image_info_from_the_header() {
+        let info = ImageInfo::from(header(640, 480, 16, 6, 0));
+        assert_eq!(info, ImageInfo {
+            width: 640,
+            height: 480,
+            bit_depth: 16,
+            channels: 4,
+            has_alpha: true,
+            color_space: ColorSpace::Rgba,
+        });
+
+        let info = ImageInfo::from(header(1, 1, 4, 3, 0));
+        assert_eq!((info.bit_depth, info.channels, info.has_alpha, info.color_space), (4, 1, false, ColorSpace::Indexed));
+
+        let info = ImageInfo::from(header(1, 1, 8, 4, 0));
+        assert_eq!((info.channels, info.has_alpha, info.color_space), (2, true, ColorSpace::GrayAlpha));
+    }
 
+    #[test]
+    fn truncated_chunks_are_unexpected_eof() {
+        let file = rgb_2x2_png();
+        // the tEXt chunk follows the signature and the 25 bytes of IHDR
+        let text = 8 + 25;
+        let length = NetworkEndian::read_u32(&file[text..text + 4]) as usize;
+
+        let cuts = [
+            (text + 2, None),
+            (text + 6, None),
+            (text + 8 + length / 2, Some(ChunkKind::tEXt)),
+            (text + 8 + length + 2, Some(ChunkKind::tEXt)),
+        ];
+
+        for &(cut, chunk_kind) in cuts.iter() {
+            for &crc_validation in [true, false].iter() {
+                let mut decoder = Decoder::new(Cursor::new(file[..cut].to_vec()));
+                decoder.set_crc_validation(crc_validation);
+                decoder.read_header().unwrap();
+
+                match decoder.read_chunk() {
+                    Err(Error::UnexpectedEof { chunk_kind: kind }) if kind == chunk_kind => { },
+                    other => panic!("cut at {}: {:?}", cut, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_pixel_mean_matches_the_full_buffer() {
+        // a red gradient across, green down
+        let rows: Vec<Vec<u8>> = (0..5u8).map(|y| {
+            (0..7u8).flat_map(|x| vec![x * 36, y * 60, 7]).collect()
+        }).collect();
+        let file = png(&[
+            (ChunkKind::IHDR, &header_data(7, 5, 8, 2, 0)),
+            (ChunkKind::IDAT, &zlib(&filter_rows(3, &rows, &[FilterType::Sub, FilterType::Paeth]))),
+            (ChunkKind::IEND, &[]),
+        ]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        let mut red = 0u64;
+        let mut count = 0u64;
+        decoder.for_each_pixel(|x, y, pixel| {
+            assert_eq!(pixel.len(), 3);
+            assert_eq!(pixel, &rows[y as usize][x as usize * 3..x as usize * 3 + 3]);
+            red += pixel[0] as u64;
+            count += 1;
+        }).unwrap();
+
+        let data = decoder.read_image().unwrap();
+        let pixels = unfilter(&decoder.header().unwrap(), &data).unwrap();
+        let full_red: u64 = pixels.chunks(3).map(|pixel| pixel[0] as u64).sum();
+
+        assert_eq!(count, 35);
+        assert_eq!(red / count, full_red / 35);
+        assert_eq!(red, full_red);
+    }
+
+    #[test]
+    fn color_type_and_bit_depth_after_the_header() {
+        let mut decoder = Decoder::new(Cursor::new(rgb_2x2_png()));
+        assert_eq!((decoder.color_type(), decoder.bit_depth()), (None, None));
+
+        decoder.read_header().unwrap();
+        assert_eq!(decoder.color_type(), Some(Color::Truecolour));
+        assert_eq!(decoder.bit_depth(), Some(BitDepth::Eight));
+    }
+}
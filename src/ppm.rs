@@ -4,8 +4,12 @@
 // http://netpbm.sourceforge.net/doc/ppm.html
 
 mod netpbm;
+mod info;
 
 pub use self::netpbm::{ PPM_ASCII_MAGIC_NUMBER, PPM_BINARY_MAGIC_NUMBER, Lines };
+use self::netpbm::{ PBM_ASCII_MAGIC_NUMBER, PGM_ASCII_MAGIC_NUMBER, PBM_BINARY_MAGIC_NUMBER, PGM_BINARY_MAGIC_NUMBER };
+use self::netpbm::read_raster;
+pub use self::info::{ ImageInfo, ColorSpace, Origin, PixelLayout };
 
 use std::io;
 use std::fmt;
@@ -38,11 +42,89 @@ impl From<io::Error> for Error {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Header {
+    /// P1 to P6, PBM and PGM share the decoder with PPM
+    pub magic: [u8; 2],
     pub width: u64,
     pub height: u64,
+    /// always 1 for PBM
     pub maxval: u16,
 }
 
+impl Header {
+    /// Short description for display, e.g. "8-bit RGB", "16-bit Grayscale" or
+    /// "1-bit Black and White".
+    pub fn color_and_depth_description(&self) -> String {
+        let bitdepth = 16 - self.maxval.leading_zeros();
+        let color = match self.magic {
+            PBM_ASCII_MAGIC_NUMBER | PBM_BINARY_MAGIC_NUMBER => "Black and White",
+            PGM_ASCII_MAGIC_NUMBER | PGM_BINARY_MAGIC_NUMBER => "Grayscale",
+            _ => "RGB",
+        };
+
+        format!("{}-bit {}", bitdepth, color)
+    }
+
+    fn is_bitmap(&self) -> bool {
+        self.magic == PBM_ASCII_MAGIC_NUMBER || self.magic == PBM_BINARY_MAGIC_NUMBER
+    }
+
+    /// Whether the pixel data is binary (P4, P5, P6) rather than plain text.
+    pub fn is_binary(&self) -> bool {
+        match self.magic {
+            PBM_BINARY_MAGIC_NUMBER | PGM_BINARY_MAGIC_NUMBER | PPM_BINARY_MAGIC_NUMBER => true,
+            _ => false,
+        }
+    }
+
+    /// Samples per pixel, 1 for PBM and PGM and 3 for PPM.
+    pub fn channels(&self) -> u8 {
+        match self.magic {
+            PPM_ASCII_MAGIC_NUMBER | PPM_BINARY_MAGIC_NUMBER => 3,
+            _ => 1,
+        }
+    }
+
+    /// Bytes per sample of binary pixel data, 2 (big-endian) when maxval is above 255.
+    pub fn sample_size(&self) -> u64 {
+        if self.maxval > 255 { 2 } else { 1 }
+    }
+
+    pub fn bytes_per_pixel(&self) -> u64 {
+        self.channels() as u64 * self.sample_size()
+    }
+
+    /// Bytes in a row of binary pixel data: PBM packs 8 pixels a byte, a row starts on
+    /// a byte boundary. `None` if it overflows.
+    pub fn row_stride(&self) -> Option<u64> {
+        if self.is_bitmap() {
+            Some(self.width / 8 + (self.width % 8 != 0) as u64)
+        } else {
+            self.width.checked_mul(self.bytes_per_pixel())
+        }
+    }
+
+    /// Layout of binary pixel data: top-left origin, rows of `row_stride` bytes without padding.
+    pub fn pixel_layout(&self) -> PixelLayout {
+        PixelLayout {
+            origin: Origin::TopLeft,
+            row_stride: self.row_stride().unwrap_or(0) as usize,
+        }
+    }
+}
+
+impl From<Header> for ImageInfo {
+    fn from(header: Header) -> ImageInfo {
+        ImageInfo {
+            width: header.width,
+            height: header.height,
+            bit_depth: info::maxval_bit_depth(header.maxval),
+            channels: header.channels(),
+            has_alpha: false,
+            color_space: if header.channels() == 3 { ColorSpace::Rgb } else { ColorSpace::Gray },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Data {
     pub offset: u64,
@@ -58,9 +140,15 @@ pub enum State {
     Data,
 }
 
+#[derive(Clone)]
 pub struct Decoder<RS: Read + Seek> {
     state: State,
     line_reader: Lines<RS>,
+    signature: Option<[u8; 2]>,
+    header: Option<Header>,
+    data: Option<Data>,
+    /// next row `read_rows` returns
+    row: u64,
     pixels_size: u64,
 }
 
@@ -70,29 +158,84 @@ impl<RS: Read + Seek> Decoder<RS> {
         Decoder {
             state: State::Pending,
             line_reader: Lines { handle: handle },
+            signature: None,
+            header: None,
+            data: None,
+            row: 0,
             pixels_size: 0,
         }
     }
 
+    /// Bytes per sample of the pixel data, `None` before the header is read.
+    pub fn sample_size(&self) -> Option<usize> {
+        self.header.map(|header| header.sample_size() as usize)
+    }
+
+    /// Decoder positioned at `row` of binary (P4, P5, P6) pixel data, `header` and `data` coming
+    /// from an earlier read of the same file. Lets workers read disjoint row ranges.
+    pub fn resume(handle: RS, header: &Header, data: &Data, row: u64) -> Result<Self, Error> {
+        let mut decoder = Decoder {
+            state: State::Data,
+            line_reader: Lines { handle: handle },
+            signature: Some(header.magic),
+            header: Some(*header),
+            data: Some(*data),
+            row: 0,
+            pixels_size: data.length,
+        };
+
+        decoder.seek_to_row(row)?;
+
+        Ok(decoder)
+    }
+
+    /// Gives back the underlying handle, e.g. after iterating with `for elem in &mut decoder`.
+    pub fn into_inner(self) -> RS {
+        self.line_reader.into_handle()
+    }
+
+    /// Reads the signature from the current position of the handle, the decoder does not
+    /// rewind. Once read the signature is cached and later calls return it without reading.
     pub fn read_signature(&mut self) -> Result<[u8; 2], Error> {
+        if let Some(signature) = self.signature {
+            return Ok(signature);
+        }
+
         assert_eq!(self.state, State::Pending);
-        self.line_reader.handle.seek(SeekFrom::Start(0)).unwrap();
 
         if let Some(line) = self.line_reader.next() {
-            if line.len() == 2 {
+            if line.len() == 2 && line[0] == b'P' && line[1] >= b'1' && line[1] <= b'6' {
+                let signature = [ line[0], line[1], ];
+
+                self.signature = Some(signature);
                 self.state = State::Signature;
-                return Ok([ line[0], line[1], ])
+                return Ok(signature)
             }
         }
 
         Err(Error::InvalidSignature)
     }
 
+    /// Rewinds the handle to the start of the file and forgets everything read so far.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.line_reader.handle.seek(SeekFrom::Start(0))?;
+
+        self.state = State::Pending;
+        self.signature = None;
+        self.header = None;
+        self.data = None;
+        self.row = 0;
+        self.pixels_size = 0;
+
+        Ok(())
+    }
+
     fn next_value(&mut self) -> Option<String> {
         if let Some(line) = self.line_reader.next() {
             if line.len() > 0 {
                 if line[0] == b'#' {
                     // COMMENT LINE
+                    self.line_reader.skip_comment().ok()?;
                     return self.next_value();
                 }
                 if let Ok(s) = String::from_utf8(line) {
@@ -134,7 +277,11 @@ impl<RS: Read + Seek> Decoder<RS> {
             }
         };
 
-        let maxval: u16 = {
+        let magic = self.signature.ok_or(Error::InvalidSignature)?;
+        let is_bitmap = magic == PBM_ASCII_MAGIC_NUMBER || magic == PBM_BINARY_MAGIC_NUMBER;
+
+        // PBM has no maxval line
+        let maxval: u16 = if is_bitmap { 1 } else {
             match self.next_value() {
                 Some(val) => {
                     if let Ok(v) = val.parse::<u16>() {
@@ -147,16 +294,24 @@ impl<RS: Read + Seek> Decoder<RS> {
             }
         };
 
-        if maxval < 1 || maxval > 255 {
+        if width == 0 || height == 0 || maxval < 1 {
             return Err(Error::InvalidHeader);
         }
 
-        let header = Header { width, height, maxval };
+        // Exactly one whitespace byte separates maxval from the raster,
+        // a maxval running into EOF leaves no room for pixel data.
+        self.line_reader.raster_start().map_err(|_| Error::InvalidHeader)?;
 
-        // bytes per pixel
-        let pixels_size = header.width * header.height * 3;
+        let header = Header { magic, width, height, maxval };
 
-        self.pixels_size = pixels_size;
+        let pixels_size = header.row_stride().and_then(|n| n.checked_mul(header.height));
+
+        match pixels_size {
+            Some(pixels_size) => self.pixels_size = pixels_size,
+            None => return Err(Error::InvalidHeader),
+        }
+
+        self.header = Some(header);
         self.state = State::Header;
 
         Ok(header)
@@ -164,17 +319,78 @@ impl<RS: Read + Seek> Decoder<RS> {
 
     pub fn read_data(&mut self) -> Result<Data, Error> {
         assert_eq!(self.state, State::Header);
-        assert_eq!(self.pixels_size > 0, true);
 
-        let pos = self.line_reader.handle.seek(SeekFrom::Current(0)).unwrap();
-
-        self.state = State::Data;
+        let pos = self.line_reader.position()?;
 
-        Ok(Data {
+        let data = Data {
             offset: pos,
             length: self.pixels_size,
-        })
+        };
+
+        self.data = Some(data);
+        self.row = 0;
+        self.state = State::Data;
+
+        Ok(data)
     }
+
+    /// Moves to the start of `row` in binary (P4, P5, P6) pixel data.
+    /// Rows of plain netpbm have no fixed offset.
+    pub fn seek_to_row(&mut self, row: u64) -> Result<(), Error> {
+        let (header, data) = match (self.header, self.data) {
+            (Some(header), Some(data)) => (header, data),
+            _ => return Err(Error::Other("pixel data not reached")),
+        };
+
+        if !header.is_binary() {
+            return Err(Error::Other("rows of plain netpbm are not addressable"));
+        }
+
+        if row >= header.height {
+            return Err(Error::Other("row out of bounds"));
+        }
+
+        // the whole raster fitted when the header was read
+        let row_stride = header.row_stride().unwrap_or(0);
+        self.line_reader.handle.seek(SeekFrom::Start(data.offset + row * row_stride))?;
+        self.row = row;
+
+        Ok(())
+    }
+
+    /// Reads the next `rows` rows of binary pixel data, starting at the current row.
+    pub fn read_rows(&mut self, rows: u64) -> Result<Vec<u8>, Error> {
+        let header = match (self.header, self.data) {
+            (Some(header), Some(_)) => header,
+            _ => return Err(Error::Other("pixel data not reached")),
+        };
+
+        if !header.is_binary() {
+            return Err(Error::Other("rows of plain netpbm are not addressable"));
+        }
+
+        let end = match self.row.checked_add(rows) {
+            Some(end) if end <= header.height => end,
+            _ => return Err(Error::Other("row out of bounds")),
+        };
+
+        let length = header.row_stride()
+                                 .and_then(|row_stride| row_stride.checked_mul(rows))
+                                 .ok_or(Error::Other("row out of bounds"))?;
+
+        let pixels = read_raster(&mut self.line_reader.handle, length)?;
+        self.row = end;
+
+        Ok(pixels)
+    }
+}
+
+/// Reads the signature and the header from the current position of `handle`,
+/// without touching the pixels. The handle is left at the first byte of pixel data.
+pub fn read_header_only<RS: Read + Seek>(handle: &mut RS) -> Result<Header, Error> {
+    let mut decoder = Decoder::new(handle);
+    decoder.read_signature()?;
+    decoder.read_header()
 }
 
 
@@ -256,6 +472,18 @@ impl<Handle: Read + Seek> Iterator for Decoder<Handle> {
             None
         }
     }
+
+    /// Counts the elements left of signature, header and data, fewer only if the file is malformed.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = match self.state {
+            State::Pending => 3,
+            State::Signature => 2,
+            State::Header => 1,
+            State::Data => 0,
+        };
+
+        (remaining, Some(remaining))
+    }
 }
 
 
@@ -290,4 +518,289 @@ fn main(){
             },
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn decode(file: &[u8]) -> Result<(Decoder<Cursor<Vec<u8>>>, Header), Error> {
+        let mut decoder = Decoder::new(Cursor::new(file.to_vec()));
+        decoder.read_signature()?;
+        let header = decoder.read_header()?;
+        decoder.read_data()?;
+        Ok((decoder, header))
+    }
+
+    #[test]
+    fn raster_may_start_with_a_line_break() {
+        let (mut decoder, header) = decode(b"P6 1 1 255\n\r\x01\x02").unwrap();
+        assert_eq!(decoder.read_rows(header.height).unwrap(), b"\r\x01\x02");
+
+        let (mut decoder, header) = decode(b"P6 1 1 255\r\n\x01\x02").unwrap();
+        assert_eq!(decoder.read_rows(header.height).unwrap(), b"\n\x01\x02");
+
+        let (mut decoder, header) = decode(b"P6 1 1 255 \n\x01\x02").unwrap();
+        assert_eq!(decoder.read_rows(header.height).unwrap(), b"\n\x01\x02");
+    }
+
+    #[test]
+    fn read_rows_checks_bounds() {
+        let (mut decoder, _) = decode(b"P6 1 2 255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\x01\x02\x03");
+
+        for &rows in [2, u64::max_value()].iter() {
+            match decoder.read_rows(rows) {
+                Err(Error::Other("row out of bounds")) => { },
+                other => panic!("{:?}", other),
+            }
+        }
+
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\x04\x05\x06");
+    }
+
+    #[test]
+    fn huge_header_without_data_does_not_allocate() {
+        let (mut decoder, header) = decode(b"P6 100000 100000 65535\n").unwrap();
+        match decoder.read_rows(header.height) {
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => { },
+            other => panic!("{:?}", other.map(|pixels| pixels.len())),
+        }
+    }
+
+    #[test]
+    fn maxval_at_end_of_file_is_rejected() {
+        match decode(b"P6 1 1 255") {
+            Err(Error::InvalidHeader) => { },
+            other => panic!("{:?}", other.map(|(_, header)| header)),
+        }
+    }
+
+    #[test]
+    fn plain_ppm_from_a_pipe_matches_the_binary_twin() {
+        // `&[u8]` is a `BufRead` without `Seek`, like stdin
+        let plain: &[u8] = b"P3\n# a comment\n2 2\n255\n0 1 2  3 4 5\n250 251 252\n253 254 255\n";
+        let image = netpbm::read_plain(plain).unwrap();
+
+        assert_eq!((image.width, image.height, image.maxval), (2, 2, 255));
+
+        let (mut decoder, header) = decode(b"P6 2 2 255\n\x00\x01\x02\x03\x04\x05\xfa\xfb\xfc\xfd\xfe\xff").unwrap();
+        let binary = decoder.read_rows(header.height).unwrap();
+
+        assert_eq!(image.samples, binary.iter().map(|&sample| sample as u16).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn plain_pbm_bits_need_no_separators() {
+        let image = netpbm::read_plain(&b"P1\n4 2\n0110\n1 0 0 1\n"[..]).unwrap();
+        assert_eq!(image.samples, vec![0, 1, 1, 0, 1, 0, 0, 1]);
+
+        assert!(netpbm::read_plain(&b"P2\n2 1\n3\n1 4\n"[..]).is_err());
+    }
+
+    #[test]
+    fn header_numbers_may_have_a_plus_sign_and_leading_zeros() {
+        for file in [&b"P6 1 1 +255\n\x01\x02\x03"[..], &b"P6 1 1 00255\n\x01\x02\x03"[..]].iter() {
+            let (_, header) = decode(file).unwrap();
+            assert_eq!(header, Header { magic: PPM_BINARY_MAGIC_NUMBER, width: 1, height: 1, maxval: 255 });
+        }
+
+        let (_, header) = decode(b"P6 +2 001 255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!((header.width, header.height), (2, 1));
+
+        for file in [&b"P6 1 1 -1\n\x01\x02\x03"[..], &b"P6 -1 1 255\n\x01\x02\x03"[..], &b"P6 1 1 0x1f\n\x01"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other.map(|(_, header)| header)),
+            }
+        }
+    }
+
+    #[test]
+    fn zero_dimensions_are_rejected() {
+        for file in [&b"P6 0 1 255\n"[..], &b"P6 1 0 255\n"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other.map(|(_, header)| header)),
+            }
+        }
+
+        // iterating stops instead of panicking
+        let decoder = Decoder::new(Cursor::new(b"P6 0 1 255\n".to_vec()));
+        assert_eq!(decoder.count(), 1);
+    }
+
+    #[test]
+    fn sixteen_bit_ppm_end_to_end() {
+        let mut file = b"P6\n2 1\n65535\n".to_vec();
+        file.extend_from_slice(&[0x01, 0x02, 0, 0, 0xff, 0xff, 0x12, 0x34, 0, 1, 0, 2]);
+
+        let mut decoder = Decoder::new(Cursor::new(file));
+        decoder.read_signature().unwrap();
+        let header = decoder.read_header().unwrap();
+        let data = decoder.read_data().unwrap();
+
+        assert_eq!(decoder.sample_size(), Some(2));
+        assert_eq!(header.bytes_per_pixel(), 6);
+        assert_eq!(data.length, 12);
+
+        let pixels = decoder.read_rows(1).unwrap();
+        assert_eq!(pixels.len(), 12);
+        // big-endian samples: red of the first pixel, green of the second
+        assert_eq!((pixels[0] as u16) << 8 | pixels[1] as u16, 0x0102);
+        assert_eq!((pixels[8] as u16) << 8 | pixels[9] as u16, 0x0001);
+    }
+
+    #[test]
+    fn halves_read_by_two_decoders_make_the_whole() {
+        let mut file = b"P6\n3 4\n255\n".to_vec();
+        file.extend((0..36).map(|i| i as u8 * 7));
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        decoder.read_signature().unwrap();
+        let header = decoder.read_header().unwrap();
+        let data = decoder.read_data().unwrap();
+        let whole = decoder.read_rows(header.height).unwrap();
+
+        let mut top = Decoder::resume(Cursor::new(file.clone()), &header, &data, 0).unwrap();
+        let mut bottom = Decoder::resume(Cursor::new(file.clone()), &header, &data, 2).unwrap();
+
+        let mut halves = top.read_rows(2).unwrap();
+        halves.extend(bottom.read_rows(2).unwrap());
+        assert_eq!(halves, whole);
+
+        match Decoder::resume(Cursor::new(file), &header, &data, 4) {
+            Err(Error::Other("row out of bounds")) => { },
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn description_follows_the_magic_number() {
+        let cases: [(&[u8], &str); 6] = [
+            (b"P6 1 1 255\n\0\0\0", "8-bit RGB"),
+            (b"P3 1 1 65535\n0 0 0", "16-bit RGB"),
+            (b"P5 1 1 65535\n\0\0", "16-bit Grayscale"),
+            (b"P2 1 1 15\n0", "4-bit Grayscale"),
+            (b"P4 1 1\n\0", "1-bit Black and White"),
+            (b"P1 1 1\n0", "1-bit Black and White"),
+        ];
+
+        for &(file, description) in cases.iter() {
+            let (_, header) = decode(file).unwrap();
+            assert_eq!(header.color_and_depth_description(), description);
+        }
+    }
+
+    #[test]
+    fn pgm_and_pbm_rows() {
+        let (mut decoder, header) = decode(b"P5 2 2 255\n\x01\x02\x03\x04").unwrap();
+        assert_eq!(header.bytes_per_pixel(), 1);
+        assert_eq!(decoder.read_rows(2).unwrap(), b"\x01\x02\x03\x04");
+
+        // 10 pixels a row fill 2 bytes
+        let (mut decoder, header) = decode(b"P4\n10 2\n\xff\xc0\x80\x40").unwrap();
+        assert_eq!(header.maxval, 1);
+        assert_eq!(header.row_stride(), Some(2));
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\xff\xc0");
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\x80\x40");
+
+        match decode(b"P7\n") {
+            Err(Error::InvalidSignature) => { },
+            other => panic!("{:?}", other.map(|(_, header)| header)),
+        }
+    }
+
+    #[test]
+    fn header_comments_run_to_the_end_of_the_line() {
+        let files = [
+            &b"P6\n# a comment with 3 words\n1 1\n255\n\x01\x02\x03"[..],
+            &b"P6\n#comment\r\n1 1\n255\n\x01\x02\x03"[..],
+            &b"P6 1 #\n# 2 2\n1 255\n\x01\x02\x03"[..],
+        ];
+
+        for file in files.iter() {
+            let (mut decoder, header) = decode(file).unwrap();
+            assert_eq!(header, Header { magic: PPM_BINARY_MAGIC_NUMBER, width: 1, height: 1, maxval: 255 });
+            assert_eq!(decoder.read_rows(1).unwrap(), b"\x01\x02\x03");
+        }
+
+        // a comment never ends the header
+        match decode(b"P6 1 1 # 255\n") {
+            Err(Error::InvalidHeader) => { },
+            other => panic!("{:?}", other.map(|(_, header)| header)),
+        }
+    }
+
+    #[test]
+    fn maxval_out_of_range_is_rejected() {
+        for file in [&b"P6 1 1 0\n\x01\x02\x03"[..], &b"P6 1 1 65536\n\x01\x02\x03"[..]].iter() {
+            match decode(file) {
+                Err(Error::InvalidHeader) => { },
+                other => panic!("{:?}", other.map(|(_, header)| header)),
+            }
+        }
+    }
+
+    #[test]
+    fn cloned_decoders_continue_independently() {
+        let (mut decoder, _) = decode(b"P6 1 2 255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\x01\x02\x03");
+
+        let mut clone = decoder.clone();
+        assert_eq!(clone.read_rows(1).unwrap(), b"\x04\x05\x06");
+        assert_eq!(decoder.read_rows(1).unwrap(), b"\x04\x05\x06");
+    }
+
+    #[test]
+    fn image_info_from_the_header() {
+        let (_, header) = decode(b"P6 3 2 1023\n").unwrap();
+        assert_eq!(ImageInfo::from(header), ImageInfo {
+            width: 3,
+            height: 2,
+            bit_depth: 10,
+            channels: 3,
+            has_alpha: false,
+            color_space: ColorSpace::Rgb,
+        });
+
+        let (_, header) = decode(b"P4 9 1\n\x00\x00").unwrap();
+        let info = ImageInfo::from(header);
+        assert_eq!((info.bit_depth, info.channels, info.color_space), (1, 1, ColorSpace::Gray));
+    }
+
+    #[test]
+    fn read_header_only_stops_at_the_pixels() {
+        let file = b"P6\n# comment\n2 1\n255\n\r\x01\x02\x03\x04\x05".to_vec();
+        let mut handle = Cursor::new(file.clone());
+
+        let header = read_header_only(&mut handle).unwrap();
+        assert_eq!(header, Header { magic: PPM_BINARY_MAGIC_NUMBER, width: 2, height: 1, maxval: 255 });
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone()));
+        decoder.read_signature().unwrap();
+        decoder.read_header().unwrap();
+        let data = decoder.read_data().unwrap();
+
+        assert_eq!(handle.position(), data.offset);
+        assert_eq!(handle.position(), file.len() as u64 - 6);
+    }
+
+    #[test]
+    fn size_hint_counts_the_elements_left() {
+        let mut decoder = Decoder::new(Cursor::new(b"P6 1 1 255\n\x01\x02\x03".to_vec()));
+
+        for remaining in (0..4).rev() {
+            assert_eq!(decoder.size_hint(), (remaining, Some(remaining)));
+            if remaining > 0 {
+                assert!(decoder.next().is_some());
+            }
+        }
+        assert!(decoder.next().is_none());
+
+        let decoder = Decoder::new(Cursor::new(b"P6 1 1 255\n\x01\x02\x03".to_vec()));
+        assert_eq!(decoder.collect::<Vec<Element>>().len(), 3);
+    }
+}